@@ -0,0 +1,240 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+
+/// Scores of models that already ran on a file, so tags can be regenerated without running
+/// them again. Files are stored as
+/// `<dir>/<model cache key>-<options digest>/<first two hash characters>/<hash>` and hold the
+/// number of frames and of scores per frame as little-endian u32, followed by the scores as
+/// little-endian f32. The options digest covers the settings that change the scores, so
+/// changing them doesn't reuse scores made with the old ones.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    /// Digest of the settings the scores were made with
+    options: Option<String>,
+}
+
+/// Size of the cached scores of one model
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub key: String,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            options: None,
+        }
+    }
+
+    /// Keeps scores apart by the settings in `options`, any text that changes with them
+    pub fn with_options(self, options: &str) -> Self {
+        let digest = format!("{:x}", Sha256::digest(options));
+        Self {
+            options: Some(digest[..8].to_string()),
+            ..self
+        }
+    }
+
+    fn path(&self, key: &str, hash: &str) -> PathBuf {
+        let key = match &self.options {
+            Some(options) => format!("{key}-{options}"),
+            None => key.to_string(),
+        };
+
+        self.dir
+            .join(key)
+            .join(hash.get(..2).unwrap_or(hash))
+            .join(hash)
+    }
+
+    /// Scores of every frame of a file, or `None` if the model hasn't scored it yet
+    pub fn get(&self, key: &str, hash: &str) -> Result<Option<Vec<Vec<f32>>>> {
+        let path = self.path(key, hash);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("Failed reading {}", path.display())),
+        };
+
+        let header = |index: usize| -> Result<usize> {
+            let bytes = bytes
+                .get(index * 4..index * 4 + 4)
+                .context(format!("Truncated cache file {}", path.display()))?;
+            Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+        };
+        let (frames, scores) = (header(0)?, header(1)?);
+        ensure!(
+            bytes.len() == 8 + frames * scores * 4,
+            "Truncated cache file {}",
+            path.display()
+        );
+
+        debug!("Using cached scores of {} for {}", hash, key);
+        Ok(Some(
+            bytes[8..]
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                .collect::<Vec<_>>()
+                .chunks(scores.max(1))
+                .map(<[f32]>::to_vec)
+                .collect(),
+        ))
+    }
+
+    pub fn put(&self, key: &str, hash: &str, frames: &[Vec<f32>]) -> Result<()> {
+        let scores = frames.first().map_or(0, Vec::len);
+        ensure!(
+            frames.iter().all(|frame| frame.len() == scores),
+            "Frames of {} have different numbers of scores",
+            hash
+        );
+
+        let mut bytes = Vec::with_capacity(8 + frames.len() * scores * 4);
+        bytes.extend_from_slice(&u32::try_from(frames.len())?.to_le_bytes());
+        bytes.extend_from_slice(&u32::try_from(scores)?.to_le_bytes());
+        for value in frames.iter().flatten() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        // Written next to the target and renamed, so readers never see half a file
+        let path = self.path(key, hash);
+        let temporary = path.with_extension("tmp");
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&temporary, bytes)
+            .with_context(|| format!("Failed writing {}", temporary.display()))?;
+        fs::rename(&temporary, &path).with_context(|| format!("Failed writing {}", path.display()))
+    }
+
+    pub fn stats(&self) -> Result<Vec<CacheStats>> {
+        let mut stats = Vec::new();
+
+        for model in read_dir_if_exists(&self.dir)? {
+            let mut entries = 0;
+            let mut bytes = 0;
+            for prefix in read_dir_if_exists(&model)? {
+                for file in read_dir_if_exists(&prefix)? {
+                    entries += 1;
+                    bytes += fs::metadata(file)?.len();
+                }
+            }
+
+            stats.push(CacheStats {
+                key: model
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                entries,
+                bytes,
+            });
+        }
+
+        stats.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(stats)
+    }
+
+    /// Removes the scores of every model not in `keep`, made with any options, and returns
+    /// what was removed
+    pub fn prune(&self, keep: &[String]) -> Result<Vec<CacheStats>> {
+        let removed: Vec<CacheStats> = self
+            .stats()?
+            .into_iter()
+            .filter(|stats| {
+                !keep.iter().any(|key| {
+                    stats
+                        .key
+                        .strip_prefix(key.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+                })
+            })
+            .collect();
+
+        for stats in &removed {
+            info!("Removing cached scores of {}", stats.key);
+            fs::remove_dir_all(self.dir.join(&stats.key))?;
+        }
+
+        Ok(removed)
+    }
+}
+
+fn read_dir_if_exists(dir: &Path) -> Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).context(format!("Failed reading {}", dir.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "ab01";
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let frames = vec![vec![0.1, 0.9], vec![0.2, 0.8]];
+
+        assert_eq!(cache.get("model-1", HASH).unwrap(), None);
+        cache.put("model-1", HASH, &frames).unwrap();
+        assert_eq!(cache.get("model-1", HASH).unwrap(), Some(frames));
+        assert_eq!(cache.get("model-2", HASH).unwrap(), None);
+    }
+
+    #[test]
+    fn test_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let thumbnails = Cache::new(dir.path()).with_options("Thumbnail");
+        let originals = Cache::new(dir.path()).with_options("Original");
+
+        thumbnails.put("model-1", HASH, &[vec![0.5]]).unwrap();
+        assert!(thumbnails.get("model-1", HASH).unwrap().is_some());
+        assert_eq!(originals.get("model-1", HASH).unwrap(), None);
+
+        let removed = Cache::new(dir.path())
+            .prune(&["model-1".to_string()])
+            .unwrap();
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_stats_and_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        cache.put("model-1", HASH, &[vec![0.5]]).unwrap();
+        cache.put("model-1", "cd23", &[vec![0.5]]).unwrap();
+        cache.put("model-2", HASH, &[vec![0.5, 0.5]]).unwrap();
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(
+            stats[0],
+            CacheStats {
+                key: "model-1".to_string(),
+                entries: 2,
+                bytes: 24,
+            }
+        );
+        assert_eq!(stats[1].entries, 1);
+
+        let removed = cache.prune(&["model-1".to_string()]).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].key, "model-2");
+        assert_eq!(cache.get("model-2", HASH).unwrap(), None);
+        assert!(cache.get("model-1", HASH).unwrap().is_some());
+    }
+}
@@ -1,8 +1,25 @@
-use std::path;
+use std::{path, sync::Arc};
 
-use clap::{Parser, Subcommand, ValueHint};
+use clap::{builder::RangedU64ValueParser, Parser, Subcommand, ValueHint};
 
-use crate::{DEFAULT_INTERVAL, DEFAULT_TAG_SERVICE, DEFAULT_THRESHOLD};
+use crate::{
+    cache::Cache,
+    embeddings::EmbeddingStore,
+    ensemble::EnsembleStrategy,
+    frames::{FrameAggregation, FrameOptions},
+    interrogator::{
+        Background, ExecutionProvider, InterrogatorOptions, TileMerge, TilingOptions, TtaOptions,
+        MAX_TTA_CROPS,
+    },
+    notes::ScoresNote,
+    rules::Rules,
+    tagger::{RatingOutput, Source, TaggerOptions, WriteMode},
+    thresholds::ThresholdMode,
+    utils::{parse_aspect_ratio, parse_fraction, parse_rating_stars, TagFormat, UnderscorePolicy},
+    DEFAULT_BATCH_SIZE, DEFAULT_FRAMES, DEFAULT_FRAME_MIN_FREQUENCY, DEFAULT_INTERVAL,
+    DEFAULT_RATING_STARS, DEFAULT_SCORES_NOTE_TAGS, DEFAULT_SESSIONS, DEFAULT_SIMILAR_LIMIT,
+    DEFAULT_TAG_SERVICE, DEFAULT_THRESHOLD, DEFAULT_TILE_OVERLAP,
+};
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -16,18 +33,181 @@ pub struct Args {
 
 #[derive(Parser)]
 pub struct CommonArgs {
-    /// Path to the model folder
+    /// Path to the model folder, repeat or separate with commas to combine several models
+    #[arg(env, long, value_hint = ValueHint::DirPath, required = true, value_delimiter = ',')]
+    pub model_dir: Vec<path::PathBuf>,
+
+    /// How to combine scores when using several models
+    #[arg(env, long, value_enum, default_value_t)]
+    pub ensemble_strategy: EnsembleStrategy,
+
+    /// Weight of each model for the weighted ensemble strategy, in --model-dir order
+    #[arg(env, long, value_delimiter = ',')]
+    pub model_weights: Vec<f32>,
+
+    /// Hardware backend to run the model on
+    #[arg(env, long, value_enum, default_value_t)]
+    pub execution_provider: ExecutionProvider,
+
+    /// Exit if the execution provider can't be registered instead of falling back to CPU
+    #[arg(env, long)]
+    pub strict_provider: bool,
+
+    /// Number of model sessions that run batches in parallel
+    #[arg(env, long, default_value_t = DEFAULT_SESSIONS, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub sessions: usize,
+
+    /// Threads each session uses within an operator [default: cores divided by --sessions]
+    #[arg(env, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub intra_threads: Option<usize>,
+
+    /// Threads each session uses to run independent operators in parallel
+    #[arg(env, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub inter_threads: Option<usize>,
+
+    /// Cut images into overlapping square tiles when their long side is more than this many
+    /// times their short side
+    #[arg(env, long, value_parser = parse_aspect_ratio)]
+    pub tile_aspect_ratio: Option<f32>,
+
+    /// Fraction of a tile that overlaps with the next one, up to 0.9
+    #[arg(env, long, default_value_t = DEFAULT_TILE_OVERLAP, value_parser = parse_fraction(0.9))]
+    pub tile_overlap: f32,
+
+    /// How to merge the scores of all tiles of an image
+    #[arg(env, long, value_enum, default_value_t)]
+    pub tile_merge: TileMerge,
+
+    /// Also run a horizontally flipped copy of each image and average the scores
+    #[arg(env, long)]
+    pub tta: bool,
+
+    /// Number of slightly offset crops to add to the averaged copies with --tta
+    #[arg(env, long, default_value_t = 0, requires = "tta", value_parser = RangedU64ValueParser::<usize>::new().range(0..=MAX_TTA_CROPS as u64))]
+    pub tta_crops: usize,
+
+    /// Color behind transparent pixels and around images that aren't square [default: the
+    /// model's pad color, usually white]
+    #[arg(env, long, value_enum)]
+    pub background: Option<Background>,
+
+    /// Which version of each file to download from Hydrus
+    #[arg(env, long, value_enum, default_value_t)]
+    pub source: Source,
+
+    /// Number of evenly spaced frames to tag in animated GIF, APNG and WebP files
+    #[arg(env, long, default_value_t = DEFAULT_FRAMES, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub frames: usize,
+
+    /// How to combine the scores of the frames of an animation
+    #[arg(env, long, value_enum, default_value_t)]
+    pub frame_aggregation: FrameAggregation,
+
+    /// Fraction of frames a tag needs to pass its threshold in with the frequency aggregation
+    #[arg(env, long, default_value_t = DEFAULT_FRAME_MIN_FREQUENCY, value_parser = parse_fraction(1.0))]
+    pub frame_min_frequency: f32,
+
+    /// Also tag animations when searching for untagged files
+    #[arg(env, long)]
+    pub include_animations: bool,
+
+    /// Namespace of a tag marking files as processed by the current model, such as
+    /// ai-tagger:wd-vit-tagger-v3. Automatic selection then looks for files without any marker
+    /// in the namespace instead of untagged files.
+    #[arg(env, long)]
+    pub marker_namespace: Option<String>,
+
+    /// Select files with the marker of another model instead of files not processed yet
+    #[arg(env, long, requires = "marker_namespace")]
+    pub retag_older_models: bool,
+
+    /// Hydrus search predicate that files have to match to be selected automatically, such
+    /// as "system:import time < 7 days" or "-meta:no ai". Can be given several times, or
+    /// separated by newlines in the environment variable.
+    #[arg(env, long, value_delimiter = '\n')]
+    pub query: Vec<String>,
+
+    /// Select files by --query instead of by being untagged. Files with a model marker are
+    /// still skipped when --marker-namespace is set.
+    #[arg(env, long, requires = "query")]
+    pub query_only: bool,
+
+    /// Name of an output of the first --model-dir with image embeddings to save to
+    /// --embedding-store
+    #[arg(env, long, requires = "embedding_store")]
+    pub embedding_output: Option<String>,
+
+    /// File to append the embedding of every tagged file to. Files whose scores come from
+    /// --cache-dir aren't run through the model, so they add no embedding.
+    #[arg(env, long, value_hint = ValueHint::FilePath, requires = "embedding_output")]
+    pub embedding_store: Option<path::PathBuf>,
+
+    /// Directory to cache model scores in, so tags can be regenerated without running the
+    /// model again. Scores are kept apart by the tiling, TTA, background, source and frame
+    /// settings.
     #[arg(env, long, value_hint = ValueHint::DirPath)]
-    pub model_dir: path::PathBuf,
+    pub cache_dir: Option<path::PathBuf>,
+
+    /// TOML file with tag blacklists, whitelists, aliases and conditional tags
+    #[arg(env, long, value_hint = ValueHint::FilePath)]
+    pub rules_file: Option<path::PathBuf>,
+
+    /// What to do with underscores in tag names
+    #[arg(env, long, value_enum, default_value_t)]
+    pub underscores: UnderscorePolicy,
+
+    /// Replaces underscores with the custom underscore policy
+    #[arg(env, long, required_if_eq("underscores", "custom"))]
+    pub underscore_replacement: Option<String>,
+
+    /// File with one tag per line whose underscores are always kept [default: built-in list
+    /// of kaomoji]
+    #[arg(env, long, value_hint = ValueHint::FilePath)]
+    pub underscore_exceptions: Option<path::PathBuf>,
+
+    /// Name of a note to write the model name and top tag confidences to as JSON, such as
+    /// "ai-tagger scores"
+    #[arg(env, long)]
+    pub scores_note: Option<String>,
+
+    /// Number of tags in --scores-note, including those below their threshold
+    #[arg(env, long, default_value_t = DEFAULT_SCORES_NOTE_TAGS)]
+    pub scores_note_tags: usize,
 
     /// The threshold for a tag to be used
     #[arg(env, long, default_value_t = DEFAULT_THRESHOLD)]
     pub threshold: f32,
 
+    /// JSON file with per-category and per-tag thresholds, defaults to thresholds.json in the
+    /// first model folder
+    #[arg(env, long, value_hint = ValueHint::FilePath)]
+    pub thresholds_file: Option<path::PathBuf>,
+
+    /// How to pick the threshold for each tag
+    #[arg(env, long, value_enum, default_value_t)]
+    pub threshold_mode: ThresholdMode,
+
+    /// Number of files to tag together, and most images to run through the model at once.
+    /// Tiles, --tta copies and frames each count as an image.
+    #[arg(env, long, default_value_t = DEFAULT_BATCH_SIZE, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub batch_size: usize,
+
     /// The tag service to use
     #[arg(env, long, default_value_t = String::from(DEFAULT_TAG_SERVICE))]
     pub tag_service: String,
 
+    /// Whether tags in the tag service that weren't predicted this time are deleted
+    #[arg(env, long, value_enum, default_value_t)]
+    pub write_mode: WriteMode,
+
+    /// Numerical rating service to set the rating on instead of adding a rating tag
+    #[arg(env, long)]
+    pub rating_service: Option<String>,
+
+    /// Number of stars set on --rating-service for each rating of the model
+    #[arg(env, long, value_delimiter = ',', default_value = DEFAULT_RATING_STARS, value_parser = parse_rating_stars)]
+    pub rating_stars: Vec<(String, u32)>,
+
     /// Access key for the Hydrus Client API
     #[arg(env, long)]
     pub access_key: String,
@@ -36,11 +216,102 @@ pub struct CommonArgs {
     #[arg(env, long, value_hint = ValueHint::Url)]
     pub host: String,
 
-    /// Don't commit anything to Hydrus
+    /// Don't commit anything to Hydrus. Prints the changes to each file with the replace
+    /// write mode.
     #[arg(env, short, long)]
     pub dry_run: bool,
 }
 
+impl CommonArgs {
+    pub fn interrogator_options(&self) -> InterrogatorOptions {
+        InterrogatorOptions {
+            execution_provider: self.execution_provider,
+            strict_provider: self.strict_provider,
+            tiling: self
+                .tile_aspect_ratio
+                .map(|max_aspect_ratio| TilingOptions {
+                    max_aspect_ratio,
+                    overlap: self.tile_overlap,
+                    merge: self.tile_merge,
+                }),
+            tta: self.tta.then_some(TtaOptions {
+                crops: self.tta_crops,
+            }),
+            background: self.background,
+            embedding_output: self.embedding_output.clone(),
+            batch_size: self.batch_size,
+            sessions: self.sessions,
+            intra_threads: self.intra_threads,
+            inter_threads: self.inter_threads,
+            cache: self.cache_dir.is_some(),
+        }
+    }
+
+    /// Settings that change the scores of the models, which cached scores are kept apart by
+    fn cache_options(&self) -> String {
+        let options = self.interrogator_options();
+        format!(
+            "{:?}",
+            (
+                options.tiling,
+                options.tta,
+                options.background,
+                self.source,
+                self.frames
+            )
+        )
+    }
+
+    /// Reads the rules and underscore exceptions files and opens the embedding store
+    pub fn tagger_options(&self) -> anyhow::Result<TaggerOptions> {
+        Ok(TaggerOptions {
+            source: self.source,
+            frames: FrameOptions {
+                count: self.frames,
+                aggregation: self.frame_aggregation,
+                min_frequency: self.frame_min_frequency,
+            },
+            include_animations: self.include_animations,
+            marker_namespace: self.marker_namespace.clone(),
+            retag_older_models: self.retag_older_models,
+            query: self.query.clone(),
+            query_only: self.query_only,
+            write_mode: self.write_mode,
+            rules: self
+                .rules_file
+                .as_deref()
+                .map(Rules::load)
+                .transpose()?
+                .unwrap_or_default(),
+            format: TagFormat::new(
+                self.underscores,
+                self.underscore_replacement.as_deref(),
+                self.underscore_exceptions.as_deref(),
+            )?,
+            ratings: match &self.rating_service {
+                Some(service) => RatingOutput::Service {
+                    service: service.clone(),
+                    stars: self.rating_stars.iter().cloned().collect(),
+                },
+                None => RatingOutput::Tag,
+            },
+            scores_note: self.scores_note.clone().map(|name| ScoresNote {
+                name,
+                tags: self.scores_note_tags,
+            }),
+            cache: self
+                .cache_dir
+                .as_deref()
+                .map(|dir| Cache::new(dir).with_options(&self.cache_options())),
+            embeddings: self
+                .embedding_store
+                .as_deref()
+                .map(|path| EmbeddingStore::open(path).map(Arc::new))
+                .transpose()?,
+        })
+    }
+}
+
 #[derive(clap::Args)]
 #[group(required = true, multiple = false)]
 #[clap()]
@@ -53,7 +324,7 @@ pub struct TargetImages {
     #[arg(long)]
     pub hashes: Option<Vec<String>>,
 
-    /// Tag images that are untagged in the provided tag service
+    /// Tag images that are untagged in the provided tag service, or lack a model marker
     #[arg(long)]
     pub automatic: Option<bool>,
 }
@@ -75,4 +346,42 @@ pub enum Commands {
         #[arg(env, long, default_value_t = DEFAULT_INTERVAL)]
         interval: usize,
     },
+    /// List the files whose saved embeddings are closest to the one of a file
+    Similar {
+        /// Hash of the file to compare against
+        hash: String,
+
+        /// File the embeddings were saved to
+        #[arg(env, long, value_hint = ValueHint::FilePath)]
+        embedding_store: path::PathBuf,
+
+        /// Number of files to list
+        #[arg(long, default_value_t = DEFAULT_SIMILAR_LIMIT)]
+        limit: usize,
+    },
+    /// Inspect or clean up the inference cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    /// Show the number and size of cached results per model and set of options
+    Stats {
+        /// Directory the model scores are cached in
+        #[arg(env, long, value_hint = ValueHint::DirPath)]
+        cache_dir: path::PathBuf,
+    },
+    /// Remove cached results of all models except the given ones
+    Prune {
+        /// Directory the model scores are cached in
+        #[arg(env, long, value_hint = ValueHint::DirPath)]
+        cache_dir: path::PathBuf,
+
+        /// Model folders whose cached results are kept, whatever options they were made with
+        #[arg(env, long, value_hint = ValueHint::DirPath, required = true, value_delimiter = ',')]
+        model_dir: Vec<path::PathBuf>,
+    },
 }
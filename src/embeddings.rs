@@ -0,0 +1,181 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use indexmap::IndexMap;
+use log::info;
+
+/// Pooled image features read from a model output
+pub type Embedding = Vec<f32>;
+
+/// Length of the hex encoded SHA-256 hashes Hydrus identifies files by
+const HASH_LENGTH: usize = 64;
+
+/// Append-only file of embeddings. Each record is the hex hash, the number of values as a
+/// little-endian u32 and the values as little-endian f32. Later records for a hash replace
+/// earlier ones.
+#[derive(Debug)]
+pub struct EmbeddingStore {
+    file: Mutex<File>,
+}
+
+impl EmbeddingStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed opening embedding store {}", path.display()))?;
+        info!("Saving embeddings to {}", path.display());
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn add(&self, hash: &str, embedding: &[f32]) -> Result<()> {
+        ensure!(hash.len() == HASH_LENGTH, "Invalid file hash {}", hash);
+
+        let mut record = Vec::with_capacity(HASH_LENGTH + 4 + embedding.len() * 4);
+        record.extend_from_slice(hash.as_bytes());
+        record.extend_from_slice(&u32::try_from(embedding.len())?.to_le_bytes());
+        for value in embedding {
+            record.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("Embedding store lock poisoned"))?;
+        file.write_all(&record)
+            .context("Failed writing to embedding store")
+    }
+
+    /// Reads the latest embedding of every hash in the store
+    pub fn load(path: &Path) -> Result<IndexMap<String, Embedding>> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed reading embedding store {}", path.display()))?;
+        let mut embeddings = IndexMap::new();
+        let mut rest = bytes.as_slice();
+
+        while !rest.is_empty() {
+            let truncated = || anyhow!("Truncated record in {}", path.display());
+            let (hash, tail) = rest.split_at_checked(HASH_LENGTH).ok_or_else(truncated)?;
+            let (length, tail) = tail.split_at_checked(4).ok_or_else(truncated)?;
+            let length = u32::from_le_bytes(length.try_into()?) as usize;
+            let (values, tail) = tail.split_at_checked(length * 4).ok_or_else(truncated)?;
+            rest = tail;
+
+            let embedding = values
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                .collect();
+            embeddings.insert(String::from_utf8(hash.to_vec())?, embedding);
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// Element-wise average, or `None` if there are no embeddings
+pub fn mean(embeddings: impl IntoIterator<Item = Embedding>) -> Option<Embedding> {
+    let mut embeddings = embeddings.into_iter();
+    let mut sum = embeddings.next()?;
+    let mut count = 1.0;
+
+    for embedding in embeddings {
+        for (total, value) in sum.iter_mut().zip(embedding) {
+            *total += value;
+        }
+        count += 1.0;
+    }
+
+    sum.iter_mut().for_each(|total| *total /= count);
+    Some(sum)
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    let norms = norm(a) * norm(b);
+
+    if norms > 0.0 {
+        dot / norms
+    } else {
+        0.0
+    }
+}
+
+/// The `limit` hashes with the embeddings closest to the one of `hash`, most similar first
+pub fn most_similar(
+    embeddings: &IndexMap<String, Embedding>,
+    hash: &str,
+    limit: usize,
+) -> Result<Vec<(String, f32)>> {
+    let target = embeddings
+        .get(hash)
+        .ok_or(anyhow!("No embedding stored for {}", hash))?;
+
+    let mut similar: Vec<(String, f32)> = embeddings
+        .iter()
+        .filter(|(other, _)| *other != hash)
+        .map(|(other, embedding)| (other.clone(), cosine_similarity(target, embedding)))
+        .collect();
+    similar.sort_by(|a, b| b.1.total_cmp(&a.1));
+    similar.truncate(limit);
+
+    Ok(similar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(c: char) -> String {
+        c.to_string().repeat(HASH_LENGTH)
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("embeddings.bin");
+
+        let store = EmbeddingStore::open(&path).unwrap();
+        store.add(&hash('a'), &[1.0, 0.0]).unwrap();
+        store.add(&hash('b'), &[0.5, 0.5]).unwrap();
+        store.add(&hash('a'), &[0.0, 1.0]).unwrap();
+        assert!(store.add("abc", &[1.0]).is_err());
+
+        let embeddings = EmbeddingStore::load(&path).unwrap();
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[&hash('a')], vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_most_similar() {
+        let embeddings = IndexMap::from([
+            (hash('a'), vec![1.0, 0.0]),
+            (hash('b'), vec![0.0, 1.0]),
+            (hash('c'), vec![2.0, 0.1]),
+        ]);
+
+        let similar = most_similar(&embeddings, &hash('a'), 1).unwrap();
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].0, hash('c'));
+        assert!(most_similar(&embeddings, &hash('d'), 1).is_err());
+    }
+
+    #[test]
+    fn test_mean() {
+        assert_eq!(
+            mean(vec![vec![1.0, 2.0], vec![3.0, 4.0]]),
+            Some(vec![2.0, 3.0])
+        );
+        assert_eq!(mean(Vec::new()), None);
+    }
+}
@@ -0,0 +1,325 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use image::DynamicImage;
+use indexmap::IndexMap;
+use log::info;
+
+use crate::{
+    embeddings::Embedding,
+    interrogator::{Interrogation, Interrogator, InterrogatorOptions, Prediction},
+};
+
+/// How the scores of several models are combined into one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EnsembleStrategy {
+    /// Average of the models that know the tag
+    #[default]
+    Mean,
+    /// Highest score of any model
+    Max,
+    /// Average of the models that know the tag, weighted by --model-weights
+    Weighted,
+}
+
+/// One or more models whose per-tag scores are combined by tag name
+pub struct Ensemble {
+    members: Vec<(Arc<Interrogator>, f32)>,
+    strategy: EnsembleStrategy,
+    namespaces: HashMap<usize, String>,
+}
+
+/// Running totals for one tag across the models of an ensemble
+struct Score {
+    category: usize,
+    sum: f32,
+    weight: f32,
+    max: f32,
+}
+
+impl Ensemble {
+    pub fn init(
+        model_dirs: &[PathBuf],
+        options: &InterrogatorOptions,
+        strategy: EnsembleStrategy,
+        weights: &[f32],
+    ) -> Result<Self> {
+        // Embeddings of different models can't be combined, so only the first model reads one
+        let other_options = InterrogatorOptions {
+            embedding_output: None,
+            ..options.clone()
+        };
+        let members = model_dirs
+            .iter()
+            .enumerate()
+            .map(|(i, model_dir)| {
+                let options = if i == 0 { options } else { &other_options };
+                Interrogator::init(model_dir, options).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::new(members, strategy, weights)
+    }
+
+    fn new(
+        members: Vec<Arc<Interrogator>>,
+        strategy: EnsembleStrategy,
+        weights: &[f32],
+    ) -> Result<Self> {
+        ensure!(!members.is_empty(), "At least one model is required");
+
+        let weights = match strategy {
+            EnsembleStrategy::Weighted => {
+                ensure!(
+                    weights.len() == members.len(),
+                    "Got {} model weights for {} models",
+                    weights.len(),
+                    members.len()
+                );
+                weights.to_vec()
+            }
+            _ => vec![1.0; members.len()],
+        };
+
+        if members.len() > 1 {
+            info!(
+                "Combining {} models using the {:?} strategy",
+                members.len(),
+                strategy
+            );
+        }
+
+        // The first model to map a category decides its namespace
+        let mut namespaces = HashMap::new();
+        for interrogator in &members {
+            for (category, namespace) in interrogator.namespaces() {
+                namespaces
+                    .entry(*category)
+                    .or_insert_with(|| namespace.clone());
+            }
+        }
+
+        Ok(Self {
+            members: members.into_iter().zip(weights).collect(),
+            strategy,
+            namespaces,
+        })
+    }
+
+    /// Loads changed models again, otherwise returns `None`
+    pub fn reload_if_changed(&self) -> Result<Option<Self>> {
+        let mut changed = false;
+        let mut members = Vec::with_capacity(self.members.len());
+
+        for (interrogator, _) in &self.members {
+            match interrogator.reload_if_changed()? {
+                Some(reloaded) => {
+                    changed = true;
+                    members.push(Arc::new(reloaded));
+                }
+                None => members.push(interrogator.clone()),
+            }
+        }
+
+        if !changed {
+            return Ok(None);
+        }
+
+        let weights: Vec<f32> = self.members.iter().map(|(_, weight)| *weight).collect();
+        Self::new(members, self.strategy, &weights).map(Some)
+    }
+
+    /// Runs every model on `images`. Returns the results of each model per image, to be
+    /// combined with [`Ensemble::combine`], and the embedding of the first model.
+    pub fn interrogate_batch(
+        &self,
+        images: &[DynamicImage],
+    ) -> Result<Vec<(Vec<Interrogation>, Option<Embedding>)>> {
+        let mut per_model = self
+            .members
+            .iter()
+            .map(|(interrogator, _)| Ok(interrogator.interrogate_batch(images)?.into_iter()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((0..images.len())
+            .map(|_| {
+                let mut embedding = None;
+                let results = per_model
+                    .iter_mut()
+                    .filter_map(|results| {
+                        let (result, model_embedding) = results.next()?;
+                        embedding = embedding.take().or(model_embedding);
+                        Some(result)
+                    })
+                    .collect();
+                (results, embedding)
+            })
+            .collect())
+    }
+
+    /// Combines the results of all models for one image, in model order
+    pub fn combine(&self, results: Vec<Interrogation>) -> Interrogation {
+        if results.len() == 1 {
+            return results.into_iter().next().unwrap();
+        }
+
+        let results = results
+            .into_iter()
+            .zip(self.members.iter().map(|(_, weight)| *weight))
+            .collect();
+        combine(self.strategy, results)
+    }
+
+    /// Inference cache key of every model, in model order
+    pub fn cache_keys(&self) -> Result<Vec<&str>> {
+        self.members
+            .iter()
+            .map(|(interrogator, _)| interrogator.cache_key())
+            .collect()
+    }
+
+    /// Restores the result of the model at `index` from cached scores
+    pub fn decode_scores(&self, index: usize, scores: &[f32]) -> Result<Interrogation> {
+        self.members[index].0.decode_scores(scores)
+    }
+
+    /// Names of all models, joined with `+`
+    pub fn name(&self) -> String {
+        self.members
+            .iter()
+            .map(|(interrogator, _)| interrogator.name())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Largest input size of all models
+    pub fn input_size(&self) -> Result<usize> {
+        self.members
+            .iter()
+            .map(|(interrogator, _)| interrogator.input_size())
+            .try_fold(0, |size, member_size| Ok(size.max(member_size?)))
+    }
+
+    /// Hydrus namespaces for tag categories, merged from all models
+    pub fn namespaces(&self) -> &HashMap<usize, String> {
+        &self.namespaces
+    }
+}
+
+fn combine(strategy: EnsembleStrategy, results: Vec<(Interrogation, f32)>) -> Interrogation {
+    let mut ratings: Option<IndexMap<String, Score>> = None;
+    let mut tags: IndexMap<String, Score> = IndexMap::new();
+
+    for ((model_ratings, model_tags), weight) in results {
+        if let Some(model_ratings) = model_ratings {
+            let ratings = ratings.get_or_insert_with(IndexMap::new);
+            for (name, confidence) in model_ratings {
+                add_score(ratings, name, 0, confidence, weight);
+            }
+        }
+
+        for (name, prediction) in model_tags {
+            add_score(
+                &mut tags,
+                name,
+                prediction.category,
+                prediction.confidence,
+                weight,
+            );
+        }
+    }
+
+    let ratings = ratings.map(|ratings| {
+        ratings
+            .into_iter()
+            .map(|(name, score)| (name, finish(strategy, &score)))
+            .collect()
+    });
+    let tags = tags
+        .into_iter()
+        .map(|(name, score)| {
+            let prediction = Prediction {
+                category: score.category,
+                confidence: finish(strategy, &score),
+            };
+            (name, prediction)
+        })
+        .collect();
+
+    (ratings, tags)
+}
+
+fn finish(strategy: EnsembleStrategy, score: &Score) -> f32 {
+    match strategy {
+        EnsembleStrategy::Max => score.max,
+        EnsembleStrategy::Mean | EnsembleStrategy::Weighted if score.weight > 0.0 => {
+            score.sum / score.weight
+        }
+        EnsembleStrategy::Mean | EnsembleStrategy::Weighted => 0.0,
+    }
+}
+
+fn add_score(
+    scores: &mut IndexMap<String, Score>,
+    name: String,
+    category: usize,
+    confidence: f32,
+    weight: f32,
+) {
+    let score = scores.entry(name).or_insert(Score {
+        category,
+        sum: 0.0,
+        weight: 0.0,
+        max: f32::MIN,
+    });
+    score.sum += confidence * weight;
+    score.weight += weight;
+    score.max = score.max.max(confidence);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrogator::predictions;
+
+    fn combined(strategy: EnsembleStrategy, weights: [f32; 2]) -> IndexMap<String, f32> {
+        let results = vec![
+            (
+                (None, predictions(&[("1girl", 0.8), ("solo", 0.4)])),
+                weights[0],
+            ),
+            (
+                (None, predictions(&[("1girl", 0.4), ("hat", 0.6)])),
+                weights[1],
+            ),
+        ];
+
+        combine(strategy, results)
+            .1
+            .into_iter()
+            .map(|(name, prediction)| (name, prediction.confidence))
+            .collect()
+    }
+
+    #[test]
+    fn test_combine_mean() {
+        let tags = combined(EnsembleStrategy::Mean, [1.0, 1.0]);
+        assert!((tags["1girl"] - 0.6).abs() < f32::EPSILON);
+        assert_eq!(tags["solo"], 0.4);
+        assert_eq!(tags["hat"], 0.6);
+    }
+
+    #[test]
+    fn test_combine_max() {
+        let tags = combined(EnsembleStrategy::Max, [1.0, 1.0]);
+        assert_eq!(tags["1girl"], 0.8);
+    }
+
+    #[test]
+    fn test_combine_weighted() {
+        let tags = combined(EnsembleStrategy::Weighted, [3.0, 1.0]);
+        assert!((tags["1girl"] - 0.7).abs() < f32::EPSILON);
+        assert_eq!(tags["hat"], 0.6);
+    }
+}
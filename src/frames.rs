@@ -0,0 +1,276 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, DynamicImage, Frames, ImageFormat, ImageResult,
+};
+use indexmap::IndexMap;
+use log::debug;
+
+use crate::{
+    interrogator::{Interrogation, Prediction},
+    thresholds::Thresholds,
+    utils::decode_image,
+};
+
+/// How the scores of the sampled frames of an animation are combined
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FrameAggregation {
+    /// Highest score in any frame
+    #[default]
+    Max,
+    /// Keep tags that pass their threshold in at least --frame-min-frequency of the frames
+    Frequency,
+}
+
+/// Settings for tagging animated GIF, APNG and WebP files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameOptions {
+    /// Number of evenly spaced frames to sample
+    pub count: usize,
+    pub aggregation: FrameAggregation,
+    /// Fraction of frames a tag has to pass its threshold in with [`FrameAggregation::Frequency`]
+    pub min_frequency: f32,
+}
+
+/// Scores of one tag across the frames of an animation
+struct FrameScore {
+    category: usize,
+    max: f32,
+    /// Number of frames where the tag passed its threshold
+    passed: usize,
+    /// Sum of the scores in those frames
+    passed_sum: f32,
+}
+
+/// Decodes up to `count` evenly spaced frames of an animation, or the image itself if it
+/// isn't animated
+pub fn decode_frames(bytes: &[u8], count: usize) -> Result<Vec<DynamicImage>> {
+    let frames = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => sample(
+            || Ok(GifDecoder::new(Cursor::new(bytes))?.into_frames()),
+            count,
+        )?,
+        Ok(ImageFormat::Png) if PngDecoder::new(Cursor::new(bytes))?.is_apng()? => sample(
+            || Ok(PngDecoder::new(Cursor::new(bytes))?.apng()?.into_frames()),
+            count,
+        )?,
+        Ok(ImageFormat::WebP) if WebPDecoder::new(Cursor::new(bytes))?.has_animation() => sample(
+            || Ok(WebPDecoder::new(Cursor::new(bytes))?.into_frames()),
+            count,
+        )?,
+        _ => Vec::new(),
+    };
+
+    if frames.is_empty() {
+        return Ok(vec![decode_image(bytes)?]);
+    }
+
+    Ok(frames)
+}
+
+/// Picks `count` evenly spaced frames. The animation is decoded twice, first to count its
+/// frames, so that only the sampled frames are kept in memory.
+fn sample<'a>(
+    frames: impl Fn() -> ImageResult<Frames<'a>>,
+    count: usize,
+) -> Result<Vec<DynamicImage>> {
+    let total = frames()?.count();
+    let indices = sample_indices(total, count);
+    debug!("Sampling frames {:?} of {}", indices, total);
+
+    frames()?
+        .enumerate()
+        .filter(|(index, _)| indices.contains(index))
+        .take(indices.len())
+        .map(|(_, frame)| Ok(DynamicImage::ImageRgba8(frame?.into_buffer())))
+        .collect()
+}
+
+/// Indices of the frames in the middle of `count` equal parts of an animation
+fn sample_indices(total: usize, count: usize) -> Vec<usize> {
+    let count = count.min(total);
+    (0..count)
+        .map(|part| (2 * part + 1) * total / (2 * count))
+        .collect()
+}
+
+/// Combines the results of the frames of one animation. Ratings are averaged with
+/// [`FrameAggregation::Frequency`], which drops tags that are too rare and averages the scores
+/// of the frames where the remaining tags passed their threshold.
+pub fn aggregate(
+    frames: Vec<Interrogation>,
+    options: &FrameOptions,
+    thresholds: &Thresholds,
+) -> Interrogation {
+    if frames.len() == 1 {
+        return frames.into_iter().next().unwrap();
+    }
+
+    let frame_count = frames.len() as f32;
+    let mut ratings: Option<IndexMap<String, f32>> = None;
+    let mut tags: IndexMap<String, FrameScore> = IndexMap::new();
+
+    for (frame_ratings, frame_tags) in frames {
+        if let Some(frame_ratings) = frame_ratings {
+            let ratings = ratings.get_or_insert_with(IndexMap::new);
+            for (name, confidence) in frame_ratings {
+                let rating = ratings.entry(name).or_insert(0.0);
+                *rating = match options.aggregation {
+                    FrameAggregation::Max => rating.max(confidence),
+                    FrameAggregation::Frequency => *rating + confidence / frame_count,
+                };
+            }
+        }
+
+        let frame_thresholds = thresholds.for_predictions(&frame_tags);
+        for (name, prediction) in frame_tags {
+            let passed = prediction.confidence > frame_thresholds.get(&name, prediction.category);
+            let score = tags.entry(name).or_insert(FrameScore {
+                category: prediction.category,
+                max: f32::MIN,
+                passed: 0,
+                passed_sum: 0.0,
+            });
+            score.max = score.max.max(prediction.confidence);
+            if passed {
+                score.passed += 1;
+                score.passed_sum += prediction.confidence;
+            }
+        }
+    }
+
+    let tags = tags
+        .into_iter()
+        .filter_map(|(name, score)| {
+            let frequency = score.passed as f32 / frame_count;
+            let confidence = match options.aggregation {
+                FrameAggregation::Max => score.max,
+                FrameAggregation::Frequency
+                    if score.passed > 0 && frequency >= options.min_frequency =>
+                {
+                    score.passed_sum / score.passed as f32
+                }
+                FrameAggregation::Frequency => return None,
+            };
+            Some((
+                name,
+                Prediction {
+                    category: score.category,
+                    confidence,
+                },
+            ))
+        })
+        .collect();
+
+    (ratings, tags)
+}
+
+/// Highest score of every tag in any frame, including tags that [`aggregate`] drops for not
+/// passing their threshold often enough
+pub fn max_tag_scores(frames: &[Interrogation]) -> IndexMap<String, Prediction> {
+    let mut tags: IndexMap<String, Prediction> = IndexMap::new();
+    for (_, frame_tags) in frames {
+        for (name, prediction) in frame_tags {
+            tags.entry(name.clone())
+                .and_modify(|max| max.confidence = max.confidence.max(prediction.confidence))
+                .or_insert(*prediction);
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::gif::GifEncoder, Frame, ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::interrogator::predictions;
+
+    fn frames() -> Vec<Interrogation> {
+        vec![
+            (
+                None,
+                predictions(&[("1girl", 0.9), ("smile", 0.6), ("blush", 0.2)]),
+            ),
+            (
+                None,
+                predictions(&[("1girl", 0.8), ("smile", 0.1), ("blush", 0.5)]),
+            ),
+            (
+                None,
+                predictions(&[("1girl", 0.7), ("smile", 0.5), ("blush", 0.1)]),
+            ),
+        ]
+    }
+
+    fn aggregated(aggregation: FrameAggregation) -> IndexMap<String, f32> {
+        let options = FrameOptions {
+            count: 3,
+            aggregation,
+            min_frequency: 0.5,
+        };
+
+        aggregate(frames(), &options, &Thresholds::new(0.35))
+            .1
+            .into_iter()
+            .map(|(name, prediction)| (name, prediction.confidence))
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate_max() {
+        let tags = aggregated(FrameAggregation::Max);
+        assert_eq!(tags["1girl"], 0.9);
+        assert_eq!(tags["smile"], 0.6);
+        assert_eq!(tags["blush"], 0.5);
+    }
+
+    #[test]
+    fn test_aggregate_frequency() {
+        let tags = aggregated(FrameAggregation::Frequency);
+        assert!((tags["1girl"] - 0.8).abs() < f32::EPSILON);
+        assert!((tags["smile"] - 0.55).abs() < f32::EPSILON);
+        assert!(!tags.contains_key("blush"));
+    }
+
+    #[test]
+    fn test_max_tag_scores() {
+        let tags = max_tag_scores(&frames());
+        assert_eq!(tags["1girl"].confidence, 0.9);
+        assert_eq!(tags["smile"].confidence, 0.6);
+        assert_eq!(tags["blush"].confidence, 0.5);
+    }
+
+    #[test]
+    fn test_sample_indices() {
+        assert_eq!(sample_indices(100, 4), vec![12, 37, 62, 87]);
+        assert_eq!(sample_indices(2, 4), vec![0, 1]);
+        assert_eq!(sample_indices(0, 4), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_decode_frames_gif() {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for shade in [0, 100, 200, 250] {
+                let buffer = ImageBuffer::from_pixel(2, 2, Rgba([shade, shade, shade, 255]));
+                encoder.encode_frame(Frame::new(buffer)).unwrap();
+            }
+        }
+
+        let frames = decode_frames(&bytes, 2).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].to_rgba8().get_pixel(0, 0).0[0], 100);
+        assert_eq!(frames[1].to_rgba8().get_pixel(0, 0).0[0], 250);
+    }
+
+    #[test]
+    fn test_decode_frames_still_image() {
+        let frames = decode_frames(include_bytes!("../tests/test_image.jpg"), 8).unwrap();
+        assert_eq!(frames.len(), 1);
+    }
+}
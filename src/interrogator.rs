@@ -1,27 +1,108 @@
-use std::{fs, path::Path, thread, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Instant, SystemTime},
+};
 
+use adapter::{AdapterKind, ModelAdapter};
 use anyhow::{anyhow, ensure, Result};
-use image::{
-    imageops::{self, FilterType},
-    DynamicImage, GenericImageView, ImageBuffer, Rgba,
-};
+use image::DynamicImage;
 use indexmap::IndexMap;
 use log::{debug, info};
-use ndarray::{Array, Array4};
-use ort::execution_providers::{
-    CUDAExecutionProvider, CoreMLExecutionProvider, TensorRTExecutionProvider,
-};
+use ndarray::{Array, ArrayView1, Axis, Ix2};
 use ort::inputs;
 use ort::session::{builder::GraphOptimizationLevel, Session};
+use pool::Pool;
+use preprocess::{Preprocessing, PreprocessingOverrides};
 use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::embeddings::{self, Embedding};
+
+mod adapter;
+mod pool;
+mod preprocess;
+mod provider;
+mod tiling;
+mod tta;
 
-type InterrogateReturn = Result<(Option<IndexMap<String, f32>>, IndexMap<String, f32>)>;
+pub use preprocess::Background;
+pub use provider::ExecutionProvider;
+pub use tiling::{TileMerge, TilingOptions};
+pub use tta::{TtaOptions, MAX_TTA_CROPS};
+
+/// Tag category used by WD-style tag lists for general tags
+pub const CATEGORY_GENERAL: usize = 0;
+/// Tag category used by WD-style tag lists for copyrights
+const CATEGORY_COPYRIGHT: usize = 3;
+/// Tag category used by WD-style tag lists for characters
+pub const CATEGORY_CHARACTER: usize = 4;
+/// Tag category used by WD-style tag lists for ratings
+const CATEGORY_RATING: usize = 9;
+
+/// Model description written for this tool
+const MODEL_INFO_FILE: &str = "info.json";
+/// Model file name used by Hugging Face tagger repositories
+const HF_MODEL_FILE: &str = "model.onnx";
+/// Tags file name used by Hugging Face tagger repositories
+const HF_TAGS_FILE: &str = "selected_tags.csv";
+
+pub type Interrogation = (Option<IndexMap<String, f32>>, IndexMap<String, Prediction>);
+
+/// The model's confidence for a tag, along with the tag's category from the tags file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prediction {
+    pub category: usize,
+    pub confidence: f32,
+}
+
+/// Settings for loading a model that don't come from the model folder
+#[derive(Debug, Default, Clone)]
+pub struct InterrogatorOptions {
+    pub execution_provider: ExecutionProvider,
+    /// Fail instead of falling back to CPU when the execution provider can't be registered
+    pub strict_provider: bool,
+    /// Tile images with extreme aspect ratios instead of squeezing them into one square
+    pub tiling: Option<TilingOptions>,
+    /// Average over flipped and cropped copies of each image
+    pub tta: Option<TtaOptions>,
+    /// Overrides the model's pad color, which is also used behind transparent pixels
+    pub background: Option<Background>,
+    /// Model output to read an image embedding from
+    pub embedding_output: Option<String>,
+    /// Most images, counting every tile, augmented copy and frame, to run through the model
+    /// at once
+    pub batch_size: usize,
+    /// Number of sessions that can each run a batch at the same time
+    pub sessions: usize,
+    /// Threads per session for running one operator, by default the cores split evenly
+    /// across sessions
+    pub intra_threads: Option<usize>,
+    /// Threads per session for running independent operators in parallel
+    pub inter_threads: Option<usize>,
+    /// Compute the inference cache key when loading the model
+    pub cache: bool,
+}
 
 pub struct Interrogator {
-    model: Session,
-    ratings_flag: bool,
-    number_of_ratings: usize,
-    tags: Vec<String>,
+    name: String,
+    model_dir: PathBuf,
+    model_file: PathBuf,
+    options: InterrogatorOptions,
+    fingerprint: ModelFingerprint,
+    sessions: Pool<Session>,
+    input_name: String,
+    input_dimensions: Vec<i64>,
+    adapter: Box<dyn ModelAdapter>,
+    preprocessing: Preprocessing,
+    tags: Vec<Tag>,
+    namespaces: HashMap<usize, String>,
+    /// Only computed with [`InterrogatorOptions::cache`], since it hashes the whole model file
+    cache_key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,10 +115,145 @@ struct ModelInfo {
     #[serde(rename = "tagsfile")]
     tags_file: String,
     #[serde(rename = "ratingsflag")]
-    #[serde(deserialize_with = "from_int_bool")]
+    #[serde(default, deserialize_with = "from_int_bool")]
     ratings_flag: bool,
     #[serde(rename = "numberofratings")]
+    #[serde(default)]
     number_of_ratings: usize,
+    /// Model family, which decides how input and output are handled
+    #[serde(default)]
+    adapter: AdapterKind,
+    #[serde(flatten)]
+    preprocessing: PreprocessingOverrides,
+    /// Hydrus namespaces to put tags of a given category in
+    #[serde(default = "default_namespaces")]
+    namespaces: HashMap<usize, String>,
+}
+
+impl ModelInfo {
+    /// Reads info.json, or falls back to discovering a Hugging Face style model folder
+    fn load(model_dir: &Path) -> Result<Self> {
+        let model_info_file = model_dir.join(MODEL_INFO_FILE);
+
+        if model_info_file.is_file() {
+            Ok(serde_json::from_str(&fs::read_to_string(model_info_file)?)?)
+        } else {
+            Self::discover(model_dir)
+        }
+    }
+
+    /// Builds model info for a folder containing model.onnx and selected_tags.csv. Ratings are
+    /// taken to be the category 9 tags at the start of the tags file.
+    fn discover(model_dir: &Path) -> Result<Self> {
+        ensure!(
+            model_dir.join(HF_MODEL_FILE).is_file() && model_dir.join(HF_TAGS_FILE).is_file(),
+            "{} has no {} and no {} and {} to fall back to",
+            model_dir.display(),
+            MODEL_INFO_FILE,
+            HF_MODEL_FILE,
+            HF_TAGS_FILE
+        );
+
+        let mut csv_rdr = csv::Reader::from_path(model_dir.join(HF_TAGS_FILE))?;
+        let number_of_ratings = csv_rdr
+            .deserialize()
+            .map_while(|result: Result<Tag, csv::Error>| result.ok())
+            .take_while(|tag| tag.category == CATEGORY_RATING)
+            .count();
+
+        let name = model_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from(HF_MODEL_FILE));
+
+        Ok(Self {
+            name,
+            model_file: String::from(HF_MODEL_FILE),
+            source: String::from("Hugging Face"),
+            tags_file: String::from(HF_TAGS_FILE),
+            ratings_flag: number_of_ratings > 0,
+            number_of_ratings,
+            adapter: AdapterKind::default(),
+            preprocessing: PreprocessingOverrides::default(),
+            namespaces: default_namespaces(),
+        })
+    }
+}
+
+fn default_namespaces() -> HashMap<usize, String> {
+    HashMap::from([
+        (CATEGORY_COPYRIGHT, String::from("series")),
+        (CATEGORY_CHARACTER, String::from("character")),
+    ])
+}
+
+/// Identifies a model in the inference cache: its name and the start of the SHA-256 of its
+/// model file, info.json and tags file, which all change what the scores mean
+pub fn cache_key(model_dir: &Path) -> Result<String> {
+    model_cache_key(model_dir, &ModelInfo::load(model_dir)?)
+}
+
+fn model_cache_key(model_dir: &Path, model_info: &ModelInfo) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(
+        &mut File::open(model_dir.join(&model_info.model_file))?,
+        &mut hasher,
+    )?;
+    let model_info_file = model_dir.join(MODEL_INFO_FILE);
+    if model_info_file.is_file() {
+        io::copy(&mut File::open(model_info_file)?, &mut hasher)?;
+    }
+    io::copy(
+        &mut File::open(model_dir.join(&model_info.tags_file))?,
+        &mut hasher,
+    )?;
+    let digest = format!("{:x}", hasher.finalize());
+
+    let name: String = model_info
+        .name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    Ok(format!("{}-{}", name, &digest[..16]))
+}
+
+/// Confidences of `interrogation` in a fixed order, ratings first, for the inference cache
+pub fn to_scores(interrogation: &Interrogation) -> Vec<f32> {
+    let (ratings, tags) = interrogation;
+    ratings
+        .iter()
+        .flat_map(|ratings| ratings.values().copied())
+        .chain(tags.values().map(|prediction| prediction.confidence))
+        .collect()
+}
+
+/// Modification times of the files that make up a loaded model
+#[derive(Debug, PartialEq, Eq)]
+struct ModelFingerprint {
+    info_modified: Option<SystemTime>,
+    model_modified: SystemTime,
+}
+
+impl ModelFingerprint {
+    fn read(model_dir: &Path, model_file: &Path) -> Result<Self> {
+        let info_modified = fs::metadata(model_dir.join(MODEL_INFO_FILE))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let model_modified = fs::metadata(model_file)?.modified()?;
+
+        Ok(Self {
+            info_modified,
+            model_modified,
+        })
+    }
+
+    /// Whether the files were modified since this fingerprint was read
+    fn has_changed(&self, model_dir: &Path, model_file: &Path) -> Result<bool> {
+        Ok(Self::read(model_dir, model_file)? != *self)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,107 +279,429 @@ where
     }
 }
 
-fn prepare_image(image: &DynamicImage, size: u32) -> Result<Array4<f32>> {
-    let white = Rgba([255, 255, 255, 255]);
-    let image = image.resize(size, size, FilterType::Lanczos3);
-    let (width, height) = image.dimensions();
-    let mut square_image = ImageBuffer::from_pixel(size, size, white);
-    let x_offset = (size - width) / 2;
-    let y_offset = (size - height) / 2;
-    imageops::overlay(&mut square_image, &image, x_offset.into(), y_offset.into());
-    let image = DynamicImage::ImageRgba8(square_image);
-    let mut input = Array::zeros((1, size.try_into()?, size.try_into()?, 3));
-
-    for pixel in image.pixels() {
-        let x = pixel.0 as usize;
-        let y = pixel.1 as usize;
-        let [r, g, b, _] = pixel.2 .0;
-        input[[0, y, x, 0]] = f32::from(b);
-        input[[0, y, x, 1]] = f32::from(g);
-        input[[0, y, x, 2]] = f32::from(r);
-    }
-
-    Ok(input)
-}
-
 impl Interrogator {
-    pub fn init(model_dir: &Path) -> Result<Self> {
+    pub fn init(model_dir: &Path, options: &InterrogatorOptions) -> Result<Self> {
         ensure!(
             model_dir.is_dir(),
             "Supplied model path does not exist or is not a directory"
         );
 
-        let model_info_file = model_dir.join("info.json");
-        let model_info: ModelInfo = serde_json::from_str(&fs::read_to_string(model_info_file)?)?;
-
-        info!("Loading model {} by {}", model_info.name, model_info.source);
+        let model_info = ModelInfo::load(model_dir)?;
+        let model_file = model_dir.join(&model_info.model_file);
+        let fingerprint = ModelFingerprint::read(model_dir, &model_file)?;
 
-        let tags_file = model_dir.join(model_info.tags_file);
-        let mut csv_rdr = csv::Reader::from_path(tags_file)?;
-        let tags: Vec<String> = csv_rdr
-            .deserialize()
-            .filter_map(|result: Result<Tag, csv::Error>| result.ok().map(|tag| tag.name))
-            .collect();
-        let model_file = model_dir.join(model_info.model_file);
-        let mut execution_providers = Vec::new();
+        if fingerprint.info_modified.is_none() {
+            info!(
+                "No {} in {}, using {} and {} with {} ratings",
+                MODEL_INFO_FILE,
+                model_dir.display(),
+                HF_MODEL_FILE,
+                HF_TAGS_FILE,
+                model_info.number_of_ratings
+            );
+        }
+        info!(
+            "Loading model {} by {} using the {:?} adapter",
+            model_info.name, model_info.source, model_info.adapter
+        );
 
-        #[cfg(target_os = "macos")]
-        execution_providers.push(CoreMLExecutionProvider::default().build());
+        let adapter = adapter::from_model_info(&model_info);
+        let tags = adapter.load_labels(model_dir)?;
+        let preprocessing = adapter
+            .preprocessing()
+            .with_overrides(&model_info.preprocessing)
+            .with_background(options.background);
+        debug!("Preprocessing: {:?}", preprocessing);
 
-        #[cfg(any(target_os = "linux", target_os = "windows"))]
-        {
-            execution_providers.push(TensorRTExecutionProvider::default().build());
-            execution_providers.push(CUDAExecutionProvider::default().build());
+        let session_count = options.sessions.max(1);
+        let intra_threads = match options.intra_threads {
+            Some(intra_threads) => intra_threads,
+            None => (thread::available_parallelism()?.get() / session_count).max(1),
+        };
+        let mut builder = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(intra_threads)?;
+        if let Some(inter_threads) = options.inter_threads {
+            builder = builder
+                .with_parallel_execution(true)?
+                .with_inter_threads(inter_threads)?;
         }
+        let builder =
+            provider::register(builder, options.execution_provider, options.strict_provider)?;
+        let sessions = (0..session_count)
+            .map(|_| builder.clone().commit_from_file(&model_file))
+            .collect::<ort::Result<Vec<_>>>()?;
+        info!(
+            "Loaded {} sessions with {} intra-op threads each",
+            session_count, intra_threads
+        );
 
-        let model = Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(thread::available_parallelism()?.get())?
-            .with_execution_providers(execution_providers)?
-            .commit_from_file(model_file)?;
+        let input = &sessions[0].inputs[0];
+        let input_name = input.name.clone();
+        let input_dimensions = input
+            .input_type
+            .tensor_dimensions()
+            .ok_or(anyhow!("No input tensor dimensions"))?
+            .clone();
+
+        let cache_key = options
+            .cache
+            .then(|| model_cache_key(model_dir, &model_info))
+            .transpose()?;
 
         Ok(Interrogator {
-            model,
-            ratings_flag: model_info.ratings_flag,
-            number_of_ratings: model_info.number_of_ratings,
+            name: model_info.name,
+            model_dir: model_dir.to_path_buf(),
+            model_file,
+            options: options.clone(),
+            fingerprint,
+            sessions: Pool::new(sessions),
+            input_name,
+            input_dimensions,
+            adapter,
+            preprocessing,
             tags,
+            namespaces: model_info.namespaces,
+            cache_key,
         })
     }
 
-    pub fn interrogate(&self, image: &DynamicImage) -> InterrogateReturn {
-        let size = self.model.inputs[0]
-            .input_type
-            .tensor_dimensions()
-            .ok_or(anyhow!("No input tensor dimensions"))?[1];
+    /// Whether info.json or the model file changed on disk since this model was loaded
+    pub fn has_changed(&self) -> Result<bool> {
+        self.fingerprint
+            .has_changed(&self.model_dir, &self.model_file)
+    }
 
-        let input = prepare_image(image, size.try_into()?)?;
-        let input_name = &self.model.inputs[0].name;
-        let time = Instant::now();
-        let outputs = self.model.run(inputs![input_name => input.view()]?)?;
-        debug!("Inference took {} s", time.elapsed().as_secs_f64());
-        let output = &outputs[0];
-        let confidences = output.try_extract_tensor::<f32>()?.to_owned();
-        let mut result = IndexMap::new();
+    /// Loads the model again if its files changed, otherwise returns `None`
+    pub fn reload_if_changed(&self) -> Result<Option<Self>> {
+        if !self.has_changed()? {
+            return Ok(None);
+        }
+
+        info!("Model in {} changed, reloading", self.model_dir.display());
+        Self::init(&self.model_dir, &self.options).map(Some)
+    }
 
-        for (tag, &confidence) in self.tags.iter().zip(confidences.iter()) {
-            result.insert(tag.clone(), confidence);
+    /// Runs the model on all `images`, stacked along the batch dimension in runs of at most
+    /// `batch_size`. Tiles and test-time augmentations add more batch entries, whose results
+    /// and embeddings are merged again.
+    pub fn interrogate_batch(
+        &self,
+        images: &[DynamicImage],
+    ) -> Result<Vec<(Interrogation, Option<Embedding>)>> {
+        let views: Vec<Vec<Vec<Cow<DynamicImage>>>> =
+            images.iter().map(|image| self.views(image)).collect();
+        let flattened: Vec<&DynamicImage> = views
+            .iter()
+            .flatten()
+            .flatten()
+            .map(AsRef::as_ref)
+            .collect();
+        let mut results = Vec::with_capacity(flattened.len());
+        for chunk in flattened.chunks(self.options.batch_size.max(1)) {
+            results.extend(self.run(chunk)?);
         }
+        let mut results = results.into_iter();
+        let tile_merge = self
+            .options
+            .tiling
+            .map(|tiling| tiling.merge)
+            .unwrap_or_default();
 
-        if self.ratings_flag {
-            let mut ratings = IndexMap::new();
-            let mut regular_tags = IndexMap::new();
+        Ok(views
+            .iter()
+            .map(|tiles| {
+                let (tiles, embeddings): (Vec<_>, Vec<_>) = tiles
+                    .iter()
+                    .map(|augmented| {
+                        let (results, embeddings): (Vec<_>, Vec<_>) =
+                            results.by_ref().take(augmented.len()).unzip();
+                        (
+                            tiling::merge(results, TileMerge::Mean),
+                            embeddings::mean(embeddings.into_iter().flatten()),
+                        )
+                    })
+                    .unzip();
+                (
+                    tiling::merge(tiles, tile_merge),
+                    embeddings::mean(embeddings.into_iter().flatten()),
+                )
+            })
+            .collect())
+    }
+
+    /// The images the model actually sees for `image`: one group of augmented copies per tile
+    fn views<'a>(&self, image: &'a DynamicImage) -> Vec<Vec<Cow<'a, DynamicImage>>> {
+        let tiles = self
+            .options
+            .tiling
+            .and_then(|tiling| tiling::tiles(image, &tiling));
 
-            for (key, value) in result {
-                if ratings.len() < self.number_of_ratings {
-                    ratings.insert(key, value);
-                } else {
-                    regular_tags.insert(key, value);
-                }
+        let tiles = match tiles {
+            Some(tiles) => {
+                info!(
+                    "Using {} tiles for {}x{} image",
+                    tiles.len(),
+                    image.width(),
+                    image.height()
+                );
+                tiles.into_iter().map(Cow::Owned).collect()
             }
+            None => vec![Cow::Borrowed(image)],
+        };
 
-            Ok((Some(ratings), regular_tags))
-        } else {
-            Ok((None, result))
+        tiles
+            .into_iter()
+            .map(|tile| {
+                let augmented = self
+                    .options
+                    .tta
+                    .map(|tta| tta::augment(&tile, &tta))
+                    .unwrap_or_default();
+                std::iter::once(tile)
+                    .chain(augmented.into_iter().map(Cow::Owned))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn run(&self, images: &[&DynamicImage]) -> Result<Vec<(Interrogation, Option<Embedding>)>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let size = self.input_size()?;
+
+        let mut input = Array::zeros(self.preprocessing.input_shape(images.len(), size));
+        for (image, slot) in images.iter().zip(input.axis_iter_mut(Axis(0))) {
+            self.preprocessing
+                .prepare_image(image, size.try_into()?, slot);
         }
+        let input = self.preprocessing.to_input_value(input)?;
+
+        let session = self.sessions.get()?;
+        let time = Instant::now();
+        let outputs = session.run(inputs![self.input_name.as_str() => input]?)?;
+        debug!(
+            "Inference of {} images took {} s",
+            images.len(),
+            time.elapsed().as_secs_f64()
+        );
+        let output = &outputs[0];
+        let scores = output
+            .try_extract_tensor::<f32>()?
+            .into_dimensionality::<Ix2>()?;
+        ensure!(
+            scores.nrows() == images.len(),
+            "Model returned {} results for a batch of {} images",
+            scores.nrows(),
+            images.len()
+        );
+
+        let embeddings: Vec<Option<Embedding>> = match &self.options.embedding_output {
+            Some(name) => {
+                let embeddings = outputs
+                    .get(name)
+                    .ok_or(anyhow!("Model has no output named {}", name))?
+                    .try_extract_tensor::<f32>()?;
+                ensure!(
+                    embeddings.shape().first() == Some(&images.len()),
+                    "Model returned embeddings of shape {:?} for a batch of {} images",
+                    embeddings.shape(),
+                    images.len()
+                );
+                embeddings
+                    .outer_iter()
+                    .map(|embedding| Some(embedding.iter().copied().collect()))
+                    .collect()
+            }
+            None => vec![None; images.len()],
+        };
+
+        Ok(scores
+            .outer_iter()
+            .map(|scores| self.adapter.decode_output(scores, &self.tags))
+            .zip(embeddings)
+            .collect())
+    }
+
+    /// Model name from info.json
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Side length of the square images the model takes
+    pub fn input_size(&self) -> Result<usize> {
+        self.preprocessing.input_size(&self.input_dimensions)
+    }
+
+    pub fn cache_key(&self) -> Result<&str> {
+        self.cache_key
+            .as_deref()
+            .ok_or(anyhow!("Model was loaded without a cache key"))
+    }
+
+    /// Restores an interrogation from the scores [`to_scores`] made of it
+    pub fn decode_scores(&self, scores: &[f32]) -> Result<Interrogation> {
+        let zeros = vec![0.0; self.tags.len()];
+        let (mut ratings, mut tags) = self
+            .adapter
+            .decode_output(ArrayView1::from(&zeros), &self.tags);
+
+        let mut values: Vec<&mut f32> = ratings
+            .iter_mut()
+            .flat_map(|ratings| ratings.values_mut())
+            .chain(
+                tags.values_mut()
+                    .map(|prediction| &mut prediction.confidence),
+            )
+            .collect();
+        ensure!(
+            values.len() == scores.len(),
+            "Got {} cached scores for {} labels",
+            scores.len(),
+            values.len()
+        );
+        for (value, score) in values.iter_mut().zip(scores) {
+            **value = *score;
+        }
+
+        Ok((ratings, tags))
+    }
+
+    /// Hydrus namespaces for tag categories, as configured for this model
+    pub fn namespaces(&self) -> &HashMap<usize, String> {
+        &self.namespaces
+    }
+}
+
+/// General tags with the given confidences, for tests
+#[cfg(test)]
+pub fn predictions(tags: &[(&str, f32)]) -> IndexMap<String, Prediction> {
+    tags.iter()
+        .map(|&(name, confidence)| {
+            let prediction = Prediction {
+                category: CATEGORY_GENERAL,
+                confidence,
+            };
+            (name.to_string(), prediction)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discover_hugging_face_layout() {
+        let model_dir = tempfile::tempdir().unwrap();
+        fs::write(model_dir.path().join(HF_MODEL_FILE), b"").unwrap();
+        fs::write(
+            model_dir.path().join(HF_TAGS_FILE),
+            "tag_id,name,category,count
+9999999,general,9,807691
+9999998,sensitive,9,3252873
+2,1girl,0,4225150
+3,hatsune_miku,4,147261
+",
+        )
+        .unwrap();
+
+        let model_info = ModelInfo::load(model_dir.path()).unwrap();
+        assert_eq!(model_info.model_file, HF_MODEL_FILE);
+        assert_eq!(model_info.tags_file, HF_TAGS_FILE);
+        assert!(model_info.ratings_flag);
+        assert_eq!(model_info.number_of_ratings, 2);
+    }
+
+    #[test]
+    fn test_info_json_wins() {
+        let model_dir = tempfile::tempdir().unwrap();
+        fs::write(model_dir.path().join(HF_MODEL_FILE), b"").unwrap();
+        fs::write(
+            model_dir.path().join(HF_TAGS_FILE),
+            "tag_id,name,category,count\n",
+        )
+        .unwrap();
+        fs::write(
+            model_dir.path().join(MODEL_INFO_FILE),
+            r#"{"modelname": "custom", "modelfile": "custom.onnx", "source": "me",
+                "tagsfile": "custom.csv", "ratingsflag": 0, "numberofratings": 0}"#,
+        )
+        .unwrap();
+
+        let model_info = ModelInfo::load(model_dir.path()).unwrap();
+        assert_eq!(model_info.name, "custom");
+        assert_eq!(model_info.model_file, "custom.onnx");
+    }
+
+    #[test]
+    fn test_discover_missing_files() {
+        let model_dir = tempfile::tempdir().unwrap();
+        assert!(ModelInfo::load(model_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let model_dir = tempfile::tempdir().unwrap();
+        let model_file = model_dir.path().join(HF_MODEL_FILE);
+        fs::write(&model_file, b"weights").unwrap();
+
+        let fingerprint = ModelFingerprint::read(model_dir.path(), &model_file).unwrap();
+        assert!(!fingerprint
+            .has_changed(model_dir.path(), &model_file)
+            .unwrap());
+
+        File::options()
+            .write(true)
+            .open(&model_file)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert!(fingerprint
+            .has_changed(model_dir.path(), &model_file)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let model_dir = tempfile::tempdir().unwrap();
+        fs::write(model_dir.path().join("model.onnx"), b"weights").unwrap();
+        fs::write(
+            model_dir.path().join("tags.csv"),
+            "tag_id,name,category,count\n",
+        )
+        .unwrap();
+        fs::write(
+            model_dir.path().join(MODEL_INFO_FILE),
+            r#"{"modelname": "WD v3/Large", "modelfile": "model.onnx", "source": "me",
+                "tagsfile": "tags.csv", "ratingsflag": 0, "numberofratings": 0}"#,
+        )
+        .unwrap();
+
+        let key = cache_key(model_dir.path()).unwrap();
+        assert_eq!(key, "WD_v3_Large-b8c6f9a5ac381acf");
+
+        fs::write(model_dir.path().join("model.onnx"), b"other weights").unwrap();
+        let model_changed = cache_key(model_dir.path()).unwrap();
+        assert_ne!(model_changed, key);
+
+        fs::write(
+            model_dir.path().join("tags.csv"),
+            "tag_id,name,category,count\n0,solo,0,1\n",
+        )
+        .unwrap();
+        let tags_changed = cache_key(model_dir.path()).unwrap();
+        assert_ne!(tags_changed, model_changed);
+
+        fs::write(
+            model_dir.path().join(MODEL_INFO_FILE),
+            r#"{"modelname": "WD v3/Large", "modelfile": "model.onnx", "source": "me",
+                "tagsfile": "tags.csv", "ratingsflag": 0, "numberofratings": 0,
+                "padcolor": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        assert_ne!(cache_key(model_dir.path()).unwrap(), tags_changed);
     }
 }
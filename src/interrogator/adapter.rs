@@ -0,0 +1,196 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use indexmap::IndexMap;
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    preprocess::{ChannelOrder, InputType, Layout, Normalization, Preprocessing, ResizeFilter},
+    Interrogation, ModelInfo, Prediction, Tag, CATEGORY_GENERAL,
+};
+
+/// Per-channel mean used to normalize JoyTag input, in RGB order
+const JOYTAG_MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+/// Per-channel standard deviation used to normalize JoyTag input, in RGB order
+const JOYTAG_STD: [f32; 3] = [0.26862954, 0.2613026, 0.2757771];
+
+/// Model family, selected with the `adapter` field in info.json
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum AdapterKind {
+    /// SmilingWolf's WD taggers
+    #[default]
+    Wd,
+    /// JoyTag by fancyfeast
+    JoyTag,
+}
+
+/// Everything that differs between tagger model families: how labels are stored, what the
+/// input tensor looks like and how the output is turned into confidences
+pub(super) trait ModelAdapter: Send + Sync {
+    /// Reads the tag list that belongs to the model's outputs
+    fn load_labels(&self, model_dir: &Path) -> Result<Vec<Tag>>;
+
+    /// Default preprocessing, which info.json can override field by field
+    fn preprocessing(&self) -> Preprocessing;
+
+    /// Turns one batch entry of the model output into ratings and tag confidences
+    fn decode_output(&self, scores: ArrayView1<f32>, tags: &[Tag]) -> Interrogation;
+}
+
+pub(super) fn from_model_info(model_info: &ModelInfo) -> Box<dyn ModelAdapter> {
+    match model_info.adapter {
+        AdapterKind::Wd => Box::new(WdAdapter {
+            tags_file: model_info.tags_file.clone(),
+            ratings_flag: model_info.ratings_flag,
+            number_of_ratings: model_info.number_of_ratings,
+        }),
+        AdapterKind::JoyTag => Box::new(JoyTagAdapter {
+            tags_file: model_info.tags_file.clone(),
+        }),
+    }
+}
+
+/// WD taggers: tags from a CSV, NHWC BGR 0-255 input and ratings as the first outputs
+struct WdAdapter {
+    tags_file: String,
+    ratings_flag: bool,
+    number_of_ratings: usize,
+}
+
+impl ModelAdapter for WdAdapter {
+    fn load_labels(&self, model_dir: &Path) -> Result<Vec<Tag>> {
+        let mut csv_rdr = csv::Reader::from_path(model_dir.join(&self.tags_file))?;
+        Ok(csv_rdr
+            .deserialize()
+            .filter_map(|result: Result<Tag, csv::Error>| result.ok())
+            .collect())
+    }
+
+    fn preprocessing(&self) -> Preprocessing {
+        Preprocessing {
+            channel_order: ChannelOrder::Bgr,
+            layout: Layout::Nhwc,
+            normalization: None,
+            pad_color: [255, 255, 255],
+            background: None,
+            resize_filter: ResizeFilter::Lanczos3,
+            input_type: InputType::F32,
+        }
+    }
+
+    fn decode_output(&self, scores: ArrayView1<f32>, tags: &[Tag]) -> Interrogation {
+        let mut ratings = IndexMap::new();
+        let mut regular_tags = IndexMap::new();
+
+        for (tag, &confidence) in tags.iter().zip(scores.iter()) {
+            if self.ratings_flag && ratings.len() < self.number_of_ratings {
+                ratings.insert(tag.name.clone(), confidence);
+            } else {
+                let prediction = Prediction {
+                    category: tag.category,
+                    confidence,
+                };
+                regular_tags.insert(tag.name.clone(), prediction);
+            }
+        }
+
+        (self.ratings_flag.then_some(ratings), regular_tags)
+    }
+}
+
+/// JoyTag: tags from a plain text file, NCHW RGB input normalized with mean/std, and logits
+/// that still need a sigmoid
+struct JoyTagAdapter {
+    tags_file: String,
+}
+
+impl ModelAdapter for JoyTagAdapter {
+    fn load_labels(&self, model_dir: &Path) -> Result<Vec<Tag>> {
+        Ok(fs::read_to_string(model_dir.join(&self.tags_file))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(id, name)| Tag {
+                id,
+                name: name.trim().to_string(),
+                category: CATEGORY_GENERAL,
+                count: 0,
+            })
+            .collect())
+    }
+
+    fn preprocessing(&self) -> Preprocessing {
+        Preprocessing {
+            channel_order: ChannelOrder::Rgb,
+            layout: Layout::Nchw,
+            normalization: Some(Normalization {
+                mean: JOYTAG_MEAN,
+                std: JOYTAG_STD,
+            }),
+            pad_color: [255, 255, 255],
+            background: None,
+            resize_filter: ResizeFilter::CatmullRom,
+            input_type: InputType::F32,
+        }
+    }
+
+    fn decode_output(&self, scores: ArrayView1<f32>, tags: &[Tag]) -> Interrogation {
+        let tags = tags
+            .iter()
+            .zip(scores.iter())
+            .map(|(tag, &logit)| {
+                let prediction = Prediction {
+                    category: tag.category,
+                    confidence: 1.0 / (1.0 + (-logit).exp()),
+                };
+                (tag.name.clone(), prediction)
+            })
+            .collect();
+
+        (None, tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    fn tag(name: &str, category: usize) -> Tag {
+        Tag {
+            id: 0,
+            name: name.to_string(),
+            category,
+            count: 0,
+        }
+    }
+
+    #[test]
+    fn test_wd_decode_output_splits_ratings() {
+        let adapter = WdAdapter {
+            tags_file: String::new(),
+            ratings_flag: true,
+            number_of_ratings: 1,
+        };
+        let tags = [tag("general", 9), tag("1girl", 0)];
+
+        let (ratings, tags) = adapter.decode_output(array![0.9, 0.8].view(), &tags);
+        assert_eq!(ratings.unwrap()["general"], 0.9);
+        assert_eq!(tags["1girl"].confidence, 0.8);
+    }
+
+    #[test]
+    fn test_joytag_decode_output_applies_sigmoid() {
+        let adapter = JoyTagAdapter {
+            tags_file: String::new(),
+        };
+        let tags = [tag("1girl", 0)];
+
+        let (ratings, tags) = adapter.decode_output(array![0.0].view(), &tags);
+        assert!(ratings.is_none());
+        assert_eq!(tags["1girl"].confidence, 0.5);
+    }
+}
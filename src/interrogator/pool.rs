@@ -0,0 +1,88 @@
+use std::{
+    ops::Deref,
+    sync::{Condvar, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Interchangeable items, such as sessions of the same model, that are checked out by one
+/// user at a time so parallel batches don't fight over the same threads
+pub(super) struct Pool<T> {
+    idle: Mutex<Vec<T>>,
+    returned: Condvar,
+}
+
+/// An item checked out of a [`Pool`], returned to it when dropped
+pub(super) struct Pooled<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+}
+
+impl<T> Pool<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        Self {
+            idle: Mutex::new(items),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Waits until an item is idle and checks it out
+    pub fn get(&self) -> Result<Pooled<'_, T>> {
+        let idle = self
+            .idle
+            .lock()
+            .map_err(|_| anyhow!("Pool lock poisoned"))?;
+        let mut idle = self
+            .returned
+            .wait_while(idle, |idle| idle.is_empty())
+            .map_err(|_| anyhow!("Pool lock poisoned"))?;
+
+        Ok(Pooled {
+            pool: self,
+            item: idle.pop(),
+        })
+    }
+}
+
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item
+            .as_ref()
+            .expect("pooled item is only taken on drop")
+    }
+}
+
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        if let (Some(item), Ok(mut idle)) = (self.item.take(), self.pool.idle.lock()) {
+            idle.push(item);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_checkout_waits_for_return() {
+        let pool = Arc::new(Pool::new(vec![1]));
+        let first = pool.get().unwrap();
+        assert_eq!(*first, 1);
+
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || *pool.get().unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        assert_eq!(waiting.join().unwrap(), 1);
+    }
+}
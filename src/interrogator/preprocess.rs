@@ -0,0 +1,367 @@
+use std::borrow::Cow;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use half::f16;
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageBuffer, Rgba, RgbaImage,
+};
+use ndarray::{Array4, ArrayViewMut3};
+use ort::value::{DynValue, Tensor};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Layout {
+    Nhwc,
+    Nchw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Color that transparent pixels are flattened onto and that images are padded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Background {
+    White,
+    Black,
+    Gray,
+    /// White behind dark images and black behind light ones, so outlines stay visible
+    Auto,
+}
+
+impl Background {
+    fn color(self, image: &DynamicImage) -> [u8; 3] {
+        match self {
+            Background::White => [255; 3],
+            Background::Black => [0; 3],
+            Background::Gray => [128; 3],
+            Background::Auto if mean_luminance(image) < 128.0 => [255; 3],
+            Background::Auto => [0; 3],
+        }
+    }
+}
+
+/// Element type of the model's input tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum InputType {
+    F32,
+    F16,
+    U8,
+}
+
+/// Rescales pixels to 0-1 and then normalizes them per channel, in the model's channel order
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(super) struct Normalization {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+/// Optional preprocessing fields in info.json that override the adapter's defaults
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct PreprocessingOverrides {
+    #[serde(rename = "channelorder")]
+    channel_order: Option<ChannelOrder>,
+    layout: Option<Layout>,
+    mean: Option<[f32; 3]>,
+    std: Option<[f32; 3]>,
+    #[serde(rename = "padcolor")]
+    pad_color: Option<[u8; 3]>,
+    #[serde(rename = "resizefilter")]
+    resize_filter: Option<ResizeFilter>,
+    #[serde(rename = "inputtype")]
+    input_type: Option<InputType>,
+}
+
+/// How an image is turned into one entry of the model's input tensor
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Preprocessing {
+    pub channel_order: ChannelOrder,
+    pub layout: Layout,
+    /// Without normalization pixels are passed as raw 0-255 values
+    pub normalization: Option<Normalization>,
+    pub pad_color: [u8; 3],
+    /// Replaces `pad_color`, also for transparent pixels
+    pub background: Option<Background>,
+    pub resize_filter: ResizeFilter,
+    pub input_type: InputType,
+}
+
+impl Preprocessing {
+    pub fn with_overrides(self, overrides: &PreprocessingOverrides) -> Self {
+        let normalization = match (overrides.mean, overrides.std) {
+            (None, None) => self.normalization,
+            (mean, std) => Some(Normalization {
+                mean: mean.unwrap_or([0.0; 3]),
+                std: std.unwrap_or([1.0; 3]),
+            }),
+        };
+
+        Self {
+            channel_order: overrides.channel_order.unwrap_or(self.channel_order),
+            layout: overrides.layout.unwrap_or(self.layout),
+            normalization,
+            pad_color: overrides.pad_color.unwrap_or(self.pad_color),
+            background: self.background,
+            resize_filter: overrides.resize_filter.unwrap_or(self.resize_filter),
+            input_type: overrides.input_type.unwrap_or(self.input_type),
+        }
+    }
+
+    pub fn with_background(self, background: Option<Background>) -> Self {
+        Self { background, ..self }
+    }
+
+    /// Picks the image size out of the model's input tensor dimensions
+    pub fn input_size(&self, dimensions: &[i64]) -> Result<usize> {
+        let index = match self.layout {
+            Layout::Nhwc => 1,
+            Layout::Nchw => 2,
+        };
+
+        dimensions
+            .get(index)
+            .ok_or(anyhow!("Input tensor has no dimension {}", index))?
+            .to_owned()
+            .try_into()
+            .map_err(|_| anyhow!("Input tensor dimension {} is not fixed", index))
+    }
+
+    /// Shape of the input tensor for a batch of `batch_size` images
+    pub fn input_shape(&self, batch_size: usize, size: usize) -> [usize; 4] {
+        match self.layout {
+            Layout::Nhwc => [batch_size, size, size, 3],
+            Layout::Nchw => [batch_size, 3, size, size],
+        }
+    }
+
+    /// Writes `image` into one batch entry of the input tensor
+    pub fn prepare_image(&self, image: &DynamicImage, size: u32, mut input: ArrayViewMut3<f32>) {
+        let background = self
+            .background
+            .map(|background| background.color(image))
+            .unwrap_or(self.pad_color);
+        let image = flatten_alpha(image, background);
+        let image = pad_to_square(&image, size, background, self.resize_filter.into());
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let (x, y) = (x as usize, y as usize);
+            let [r, g, b, _] = pixel.0;
+            let channels = match self.channel_order {
+                ChannelOrder::Rgb => [r, g, b],
+                ChannelOrder::Bgr => [b, g, r],
+            };
+
+            for (channel, value) in channels.into_iter().enumerate() {
+                let value = match &self.normalization {
+                    Some(Normalization { mean, std }) => {
+                        (f32::from(value) / 255.0 - mean[channel]) / std[channel]
+                    }
+                    None => f32::from(value),
+                };
+
+                match self.layout {
+                    Layout::Nhwc => input[[y, x, channel]] = value,
+                    Layout::Nchw => input[[channel, y, x]] = value,
+                }
+            }
+        }
+    }
+
+    /// Converts the prepared batch to the element type the model expects
+    pub fn to_input_value(&self, input: Array4<f32>) -> Result<DynValue> {
+        Ok(match self.input_type {
+            InputType::F32 => Tensor::from_array(input)?.into_dyn(),
+            InputType::F16 => Tensor::from_array(input.mapv(f16::from_f32))?.into_dyn(),
+            InputType::U8 => {
+                Tensor::from_array(input.mapv(|value| value.round().clamp(0.0, 255.0) as u8))?
+                    .into_dyn()
+            }
+        })
+    }
+}
+
+/// Blends partly transparent pixels of `image` onto `background`
+fn flatten_alpha(image: &DynamicImage, background: [u8; 3]) -> Cow<'_, DynamicImage> {
+    if !image.color().has_alpha() {
+        return Cow::Borrowed(image);
+    }
+
+    let mut flattened = image.to_rgba8();
+    for pixel in flattened.pixels_mut() {
+        let Rgba([r, g, b, a]) = *pixel;
+        let alpha = u16::from(a);
+        let blend = |value: u8, background: u8| {
+            ((u16::from(value) * alpha + u16::from(background) * (255 - alpha) + 127) / 255) as u8
+        };
+        *pixel = Rgba([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+            255,
+        ]);
+    }
+
+    Cow::Owned(DynamicImage::ImageRgba8(flattened))
+}
+
+/// Average luminance of the visible pixels of `image`, weighted by their alpha
+fn mean_luminance(image: &DynamicImage) -> f32 {
+    let (sum, weight) =
+        image
+            .pixels()
+            .fold((0.0, 0.0), |(sum, weight), (_, _, Rgba([r, g, b, a]))| {
+                let alpha = f32::from(a) / 255.0;
+                let luminance = 0.299 * f32::from(r) + 0.587 * f32::from(g) + 0.114 * f32::from(b);
+                (sum + luminance * alpha, weight + alpha)
+            });
+
+    if weight > 0.0 {
+        sum / weight
+    } else {
+        255.0
+    }
+}
+
+/// Resizes `image` to fit in a `size` square and centers it on `pad_color`
+fn pad_to_square(
+    image: &DynamicImage,
+    size: u32,
+    pad_color: [u8; 3],
+    filter: FilterType,
+) -> RgbaImage {
+    let [r, g, b] = pad_color;
+    let image = image.resize(size, size, filter);
+    let (width, height) = image.dimensions();
+    let mut square_image = ImageBuffer::from_pixel(size, size, Rgba([r, g, b, 255]));
+    let x_offset = (size - width) / 2;
+    let y_offset = (size - height) / 2;
+    imageops::overlay(&mut square_image, &image, x_offset.into(), y_offset.into());
+    square_image
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array3;
+
+    use super::*;
+
+    fn preprocessing() -> Preprocessing {
+        Preprocessing {
+            channel_order: ChannelOrder::Bgr,
+            layout: Layout::Nhwc,
+            normalization: None,
+            pad_color: [255, 255, 255],
+            background: None,
+            resize_filter: ResizeFilter::Lanczos3,
+            input_type: InputType::F32,
+        }
+    }
+
+    /// Dark line art on transparency: an opaque black pixel, a half transparent black pixel
+    /// and transparent red everywhere else
+    fn line_art() -> DynamicImage {
+        let mut image = ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 0]));
+        image.put_pixel(1, 1, Rgba([0, 0, 0, 255]));
+        image.put_pixel(2, 1, Rgba([0, 0, 0, 128]));
+        DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let overrides: PreprocessingOverrides = serde_json::from_str(
+            r#"{"layout": "nchw", "mean": [0.5, 0.5, 0.5], "padcolor": [0, 0, 0], "inputtype": "f16"}"#,
+        )
+        .unwrap();
+
+        let preprocessing = preprocessing().with_overrides(&overrides);
+        assert_eq!(preprocessing.channel_order, ChannelOrder::Bgr);
+        assert_eq!(preprocessing.layout, Layout::Nchw);
+        assert_eq!(
+            preprocessing.normalization,
+            Some(Normalization {
+                mean: [0.5; 3],
+                std: [1.0; 3]
+            })
+        );
+        assert_eq!(preprocessing.pad_color, [0, 0, 0]);
+        assert_eq!(preprocessing.input_type, InputType::F16);
+    }
+
+    #[test]
+    fn test_prepare_image_nhwc_bgr() {
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let mut input = Array3::zeros((4, 4, 3));
+
+        preprocessing().prepare_image(&image, 4, input.view_mut());
+        assert_eq!(input[[0, 0, 0]], 0.0);
+        assert_eq!(input[[0, 0, 2]], 255.0);
+    }
+
+    #[test]
+    fn test_prepare_image_nchw_normalized() {
+        let preprocessing = Preprocessing {
+            channel_order: ChannelOrder::Rgb,
+            layout: Layout::Nchw,
+            normalization: Some(Normalization {
+                mean: [0.5; 3],
+                std: [0.5; 3],
+            }),
+            ..preprocessing()
+        };
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let mut input = Array3::zeros((3, 4, 4));
+
+        preprocessing.prepare_image(&image, 4, input.view_mut());
+        assert_eq!(input[[0, 0, 0]], 1.0);
+        assert_eq!(input[[1, 0, 0]], -1.0);
+    }
+
+    #[test]
+    fn test_prepare_image_background() {
+        let mut input = Array3::zeros((4, 4, 3));
+
+        preprocessing().prepare_image(&line_art(), 4, input.view_mut());
+        assert_eq!(input[[0, 0, 0]], 255.0);
+        assert_eq!(input[[0, 0, 2]], 255.0);
+        assert_eq!(input[[1, 1, 0]], 0.0);
+        assert_eq!(input[[1, 2, 0]], 127.0);
+
+        let black = preprocessing().with_background(Some(Background::Black));
+        black.prepare_image(&line_art(), 4, input.view_mut());
+        assert_eq!(input[[0, 0, 2]], 0.0);
+        assert_eq!(input[[1, 2, 0]], 0.0);
+
+        let auto = preprocessing().with_background(Some(Background::Auto));
+        auto.prepare_image(&line_art(), 4, input.view_mut());
+        assert_eq!(input[[0, 0, 2]], 255.0);
+    }
+}
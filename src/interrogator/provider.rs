@@ -0,0 +1,151 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+#[cfg(target_os = "macos")]
+use ort::execution_providers::CoreMLExecutionProvider;
+use ort::execution_providers::{
+    CUDAExecutionProvider, ExecutionProvider as OrtExecutionProvider, ExecutionProviderDispatch,
+    OpenVINOExecutionProvider, TensorRTExecutionProvider,
+};
+use ort::session::builder::SessionBuilder;
+
+/// Hardware backend ONNX Runtime runs the model on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExecutionProvider {
+    Cpu,
+    Cuda,
+    /// TensorRT, with CUDA for the nodes it can't run
+    Tensorrt,
+    Openvino,
+    /// The accelerated providers that work on this platform, otherwise CPU
+    #[default]
+    Auto,
+}
+
+type Candidate = (&'static str, ExecutionProviderDispatch);
+
+fn candidate<E>(provider: E) -> Candidate
+where
+    E: OrtExecutionProvider + Into<ExecutionProviderDispatch>,
+{
+    (provider.as_str(), provider.into())
+}
+
+impl ExecutionProvider {
+    fn candidates(self) -> Vec<Candidate> {
+        match self {
+            Self::Cpu => Vec::new(),
+            Self::Cuda => vec![candidate(CUDAExecutionProvider::default())],
+            Self::Tensorrt => vec![
+                candidate(TensorRTExecutionProvider::default()),
+                candidate(CUDAExecutionProvider::default()),
+            ],
+            Self::Openvino => vec![candidate(OpenVINOExecutionProvider::default())],
+            #[cfg(target_os = "macos")]
+            Self::Auto => vec![candidate(CoreMLExecutionProvider::default())],
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            Self::Auto => vec![
+                candidate(TensorRTExecutionProvider::default()),
+                candidate(CUDAExecutionProvider::default()),
+            ],
+            #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+            Self::Auto => Vec::new(),
+        }
+    }
+
+    /// Whether `registered` satisfies `--strict-provider`. An explicitly requested provider has
+    /// to register itself, the ones after it are only fallbacks. `Auto` needs any of its
+    /// candidates.
+    fn strict_satisfied(self, registered: &[&str]) -> bool {
+        match self {
+            Self::Cpu => true,
+            Self::Auto => !registered.is_empty(),
+            _ => self
+                .candidates()
+                .first()
+                .is_some_and(|(name, _)| registered.contains(name)),
+        }
+    }
+}
+
+/// Registers every candidate of `provider` that works on the session, in order of preference,
+/// and logs which execution providers end up in use. With `strict` it's an error when the
+/// requested provider isn't among them.
+pub(super) fn register(
+    mut builder: SessionBuilder,
+    provider: ExecutionProvider,
+    strict: bool,
+) -> Result<SessionBuilder> {
+    let mut registered = Vec::new();
+
+    for (i, (name, candidate)) in provider.candidates().into_iter().enumerate() {
+        // Register one at a time so we know which ones ONNX Runtime actually accepted
+        match builder
+            .clone()
+            .with_execution_providers([candidate.error_on_failure()])
+        {
+            Ok(with_candidate) => {
+                builder = with_candidate;
+                registered.push(name);
+            }
+            Err(e) if i == 0 => warn!("Failed registering execution provider {}: {}", name, e),
+            Err(e) => debug!(
+                "Failed registering fallback execution provider {}: {}",
+                name, e
+            ),
+        }
+    }
+
+    if strict && !provider.strict_satisfied(&registered) {
+        bail!("Could not register the {:?} execution provider", provider);
+    }
+
+    if !registered.is_empty() {
+        info!("Using execution providers {}", registered.join(", "));
+        return Ok(builder);
+    }
+
+    if provider != ExecutionProvider::Cpu {
+        warn!("Falling back to the CPU execution provider");
+    }
+
+    info!("Using execution provider CPUExecutionProvider");
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(provider: ExecutionProvider) -> Vec<&'static str> {
+        provider
+            .candidates()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_candidates() {
+        let tensorrt = TensorRTExecutionProvider::default().as_str();
+        let cuda = CUDAExecutionProvider::default().as_str();
+
+        assert!(names(ExecutionProvider::Cpu).is_empty());
+        assert_eq!(names(ExecutionProvider::Cuda), vec![cuda]);
+        assert_eq!(names(ExecutionProvider::Tensorrt), vec![tensorrt, cuda]);
+    }
+
+    #[test]
+    fn test_strict_satisfied() {
+        let tensorrt = TensorRTExecutionProvider::default().as_str();
+        let cuda = CUDAExecutionProvider::default().as_str();
+
+        assert!(ExecutionProvider::Tensorrt.strict_satisfied(&[tensorrt, cuda]));
+        assert!(ExecutionProvider::Tensorrt.strict_satisfied(&[tensorrt]));
+        // Only the CUDA fallback registered
+        assert!(!ExecutionProvider::Tensorrt.strict_satisfied(&[cuda]));
+        assert!(!ExecutionProvider::Cuda.strict_satisfied(&[]));
+        assert!(ExecutionProvider::Cpu.strict_satisfied(&[]));
+        assert!(!ExecutionProvider::Auto.strict_satisfied(&[]));
+    }
+}
@@ -0,0 +1,163 @@
+use clap::ValueEnum;
+use image::{DynamicImage, GenericImageView};
+use indexmap::IndexMap;
+
+use super::Interrogation;
+
+/// How the scores of several views of one image are merged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TileMerge {
+    /// Highest score of any view
+    #[default]
+    Max,
+    /// Average score over all views
+    Mean,
+}
+
+/// Settings for cutting very tall or wide images into square tiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TilingOptions {
+    /// Aspect ratio (long side over short side) above which an image is tiled
+    pub max_aspect_ratio: f32,
+    /// Fraction of a tile that overlaps with the next one
+    pub overlap: f32,
+    pub merge: TileMerge,
+}
+
+/// Cuts `image` into overlapping squares along its long side, or returns `None` if its aspect
+/// ratio is within the limit
+pub(super) fn tiles(image: &DynamicImage, options: &TilingOptions) -> Option<Vec<DynamicImage>> {
+    let (width, height) = image.dimensions();
+    let (long, short) = (width.max(height), width.min(height));
+
+    if short == 0 || (long as f32 / short as f32) <= options.max_aspect_ratio {
+        return None;
+    }
+
+    let step = (short as f32 * (1.0 - options.overlap.clamp(0.0, 0.9))).max(1.0);
+    let count = ((long - short) as f32 / step).ceil() as u32 + 1;
+    let span = long - short;
+
+    Some(
+        (0..count)
+            .map(|i| {
+                let offset = match count {
+                    1 => 0,
+                    _ => (span as u64 * i as u64 / (count - 1) as u64) as u32,
+                };
+                if width > height {
+                    image.crop_imm(offset, 0, short, short)
+                } else {
+                    image.crop_imm(0, offset, short, short)
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Merges the results for several views of the same image into one
+pub(super) fn merge(results: Vec<Interrogation>, strategy: TileMerge) -> Interrogation {
+    let count = results.len() as f32;
+    let mut results = results.into_iter();
+    let Some((mut ratings, mut tags)) = results.next() else {
+        return (None, IndexMap::new());
+    };
+
+    for (view_ratings, view_tags) in results {
+        if let (Some(ratings), Some(view_ratings)) = (ratings.as_mut(), view_ratings) {
+            for (name, confidence) in view_ratings {
+                if let Some(merged) = ratings.get_mut(&name) {
+                    *merged = merge_score(*merged, confidence, strategy);
+                }
+            }
+        }
+
+        for (name, prediction) in view_tags {
+            if let Some(merged) = tags.get_mut(&name) {
+                merged.confidence = merge_score(merged.confidence, prediction.confidence, strategy);
+            }
+        }
+    }
+
+    if strategy == TileMerge::Mean {
+        for confidence in ratings.iter_mut().flat_map(|ratings| ratings.values_mut()) {
+            *confidence /= count;
+        }
+        for prediction in tags.values_mut() {
+            prediction.confidence /= count;
+        }
+    }
+
+    (ratings, tags)
+}
+
+/// Max keeps the higher score, mean sums them up to be divided by the view count afterwards
+fn merge_score(merged: f32, confidence: f32, strategy: TileMerge) -> f32 {
+    match strategy {
+        TileMerge::Max => merged.max(confidence),
+        TileMerge::Mean => merged + confidence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::interrogator::predictions;
+
+    const OPTIONS: TilingOptions = TilingOptions {
+        max_aspect_ratio: 2.0,
+        overlap: 0.25,
+        merge: TileMerge::Max,
+    };
+
+    fn image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_pixel(width, height, Rgba([0, 0, 0, 255])))
+    }
+
+    fn result(confidence: f32) -> Interrogation {
+        (None, predictions(&[("1girl", confidence)]))
+    }
+
+    #[test]
+    fn test_tiles_within_limit() {
+        assert!(tiles(&image(100, 150), &OPTIONS).is_none());
+    }
+
+    #[test]
+    fn test_tiles_just_above_limit() {
+        let options = TilingOptions {
+            max_aspect_ratio: 1.5,
+            ..OPTIONS
+        };
+        // Each row is shaded by its index, so a tile's first row shows its offset
+        let image = DynamicImage::ImageRgba8(ImageBuffer::from_fn(100, 151, |_, y| {
+            Rgba([y as u8, 0, 0, 255])
+        }));
+
+        let tiles = tiles(&image, &options).unwrap();
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].get_pixel(0, 0)[0], 0);
+        assert_eq!(tiles[1].get_pixel(0, 0)[0], 51);
+        assert_eq!(tiles[1].get_pixel(0, 99)[0], 150);
+    }
+
+    #[test]
+    fn test_tiles_tall_image() {
+        let tiles = tiles(&image(100, 1000), &OPTIONS).unwrap();
+        assert_eq!(tiles.len(), 13);
+        assert!(tiles.iter().all(|tile| tile.dimensions() == (100, 100)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let results = || vec![result(0.2), result(0.6)];
+
+        let (_, tags) = merge(results(), TileMerge::Max);
+        assert_eq!(tags["1girl"].confidence, 0.6);
+
+        let (_, tags) = merge(results(), TileMerge::Mean);
+        assert_eq!(tags["1girl"].confidence, 0.4);
+    }
+}
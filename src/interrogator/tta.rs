@@ -0,0 +1,53 @@
+use image::{DynamicImage, GenericImageView};
+
+/// Fraction of the width and height that the offset crops cut away
+const CROP_OFFSET: f32 = 0.05;
+
+/// Most offset crops there are, one anchored at each corner
+pub const MAX_TTA_CROPS: usize = 4;
+
+/// Settings for test-time augmentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtaOptions {
+    /// Number of slightly offset crops to add next to the flipped copy
+    pub crops: usize,
+}
+
+/// Extra views of `image` whose scores get averaged with the original's: a horizontally
+/// flipped copy and up to [`MAX_TTA_CROPS`] crops anchored at the corners
+pub(super) fn augment(image: &DynamicImage, options: &TtaOptions) -> Vec<DynamicImage> {
+    let (width, height) = image.dimensions();
+    let crop_width = ((width as f32 * (1.0 - CROP_OFFSET)).round() as u32).max(1);
+    let crop_height = ((height as f32 * (1.0 - CROP_OFFSET)).round() as u32).max(1);
+    let (dx, dy) = (width - crop_width, height - crop_height);
+    let corners = [(0, 0), (dx, 0), (0, dy), (dx, dy)];
+
+    std::iter::once(image.fliph())
+        .chain(
+            corners
+                .into_iter()
+                .take(options.crops.min(MAX_TTA_CROPS))
+                .map(|(x, y)| image.crop_imm(x, y, crop_width, crop_height)),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn test_augment() {
+        let mut image = ImageBuffer::from_pixel(100, 50, Rgba([0, 0, 0, 255]));
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        let image = DynamicImage::ImageRgba8(image);
+
+        let views = augment(&image, &TtaOptions { crops: 2 });
+        assert_eq!(views.len(), 3);
+        assert_eq!(views[0].get_pixel(99, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(views[1].dimensions(), (95, 48));
+        assert_eq!(views[2].get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+    }
+}
@@ -6,27 +6,43 @@ use std::{
 };
 
 use anyhow::Result;
+use cache::Cache;
 use clap::Parser;
-use cli::{Args, Commands, CommonArgs};
-use indicatif::{
-    style::ProgressTracker, HumanDuration, ParallelProgressIterator, ProgressState, ProgressStyle,
-};
-use interrogator::Interrogator;
+use cli::{Args, CacheCommands, Commands, CommonArgs};
+use embeddings::{most_similar, EmbeddingStore};
+use ensemble::Ensemble;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressState, ProgressStyle};
 use log::{error, info, warn};
 use rayon::prelude::*;
 use tagger::Tagger;
+use thresholds::Thresholds;
 use tokio::runtime::Runtime;
 use tracing_log::AsTrace;
 use utils::parse_hashes_file;
 
+mod cache;
 mod cli;
+mod embeddings;
+mod ensemble;
+mod frames;
 mod interrogator;
+mod notes;
+mod rules;
 mod tagger;
+mod thresholds;
 mod utils;
 
 const DEFAULT_THRESHOLD: f32 = 0.35;
+const DEFAULT_BATCH_SIZE: usize = 1;
+const DEFAULT_SESSIONS: usize = 1;
+const DEFAULT_TILE_OVERLAP: f32 = 0.25;
+const DEFAULT_FRAMES: usize = 8;
+const DEFAULT_FRAME_MIN_FREQUENCY: f32 = 0.5;
 const DEFAULT_TAG_SERVICE: &str = "ai tags";
 const DEFAULT_INTERVAL: usize = 60;
+const DEFAULT_SIMILAR_LIMIT: usize = 10;
+const DEFAULT_SCORES_NOTE_TAGS: usize = 50;
+const DEFAULT_RATING_STARS: &str = "general=1,sensitive=2,questionable=3,explicit=4";
 
 struct App {
     rt: Arc<Runtime>,
@@ -41,24 +57,45 @@ impl App {
         })
     }
 
+    /// Connects to Hydrus and loads the models, thresholds and everything else `common` points to
+    fn tagger(&self, common: &CommonArgs) -> Result<Tagger> {
+        let client = Arc::new(hydrus_api::Client::new(&common.host, &common.access_key));
+        let ensemble = Arc::new(Ensemble::init(
+            &common.model_dir,
+            &common.interrogator_options(),
+            common.ensemble_strategy,
+            &common.model_weights,
+        )?);
+        let thresholds = Thresholds::load(
+            common.thresholds_file.as_deref(),
+            &common.model_dir[0],
+            common.threshold,
+        )?
+        .with_mode(common.threshold_mode);
+
+        Ok(Tagger::new(
+            self.rt.clone(),
+            client,
+            ensemble,
+            thresholds,
+            common.tagger_options()?,
+        ))
+    }
+
     fn run(&self) -> Result<()> {
         match &self.args.command {
             Commands::Eval {
                 common:
-                    CommonArgs {
-                        model_dir,
-                        threshold,
+                    common @ CommonArgs {
+                        batch_size,
                         tag_service,
-                        access_key,
-                        host,
                         dry_run,
+                        ..
                     },
                 target_images,
             } => {
-                let client = Arc::new(hydrus_api::Client::new(host, access_key));
-                let interrogator = Arc::new(Interrogator::init(model_dir)?);
-                let tagger = Tagger::new(self.rt.clone(), client, model_dir.clone(), *threshold)?;
-                let service_key = tagger.get_tag_service_key_from_name(tag_service)?;
+                let tagger = self.tagger(common)?;
+                let services = tagger.service_keys(tag_service)?;
 
                 let hashes = match (
                     &target_images.hashes,
@@ -68,7 +105,7 @@ impl App {
                     (Some(hashes), _, _) => hashes,
                     (_, Some(file_path), _) => &parse_hashes_file(file_path)?,
                     (_, _, Some(automatic)) if *automatic => {
-                        &tagger.get_untagged_images(&service_key)?
+                        &tagger.get_untagged_images(&services.tags)?
                     }
                     _ => {
                         warn!("Not doing anything");
@@ -92,13 +129,15 @@ impl App {
 
                 let start_time = Instant::now();
 
+                let progress = ProgressBar::new(hashes.len() as u64).with_style(style);
+
                 println!("Tagging images");
-                hashes
-                    .par_iter()
-                    .progress_with_style(style)
-                    .try_for_each(|hash| {
-                        tagger.tag_image(&service_key, hash, *dry_run).map(|_| ())
-                    })?;
+                hashes.par_chunks(*batch_size).try_for_each(|batch| {
+                    tagger.tag_images(&services, batch, *dry_run)?;
+                    progress.inc(batch.len() as u64);
+                    Ok::<_, anyhow::Error>(())
+                })?;
+                progress.finish();
 
                 println!("Done in {}", HumanDuration(start_time.elapsed()));
 
@@ -106,18 +145,16 @@ impl App {
             }
             Commands::Daemon {
                 common:
-                    CommonArgs {
-                        model_dir,
-                        threshold,
+                    common @ CommonArgs {
+                        batch_size,
                         tag_service,
-                        access_key,
-                        host,
                         dry_run,
+                        ..
                     },
                 interval,
             } => {
                 let interval_duration = Duration::from_secs((interval * 60) as u64);
-                let client = Arc::new(hydrus_api::Client::new(host, access_key));
+                let mut tagger = self.tagger(common)?;
 
                 if *dry_run {
                     warn!("Not actually adding tags");
@@ -125,23 +162,20 @@ impl App {
 
                 loop {
                     let start_time = Instant::now();
-                    let tagger = Tagger::new(
-                        self.rt.clone(),
-                        client.clone(),
-                        model_dir.clone(),
-                        *threshold,
-                    )?;
-                    let service_key = tagger.get_tag_service_key_from_name(tag_service)?;
-
-                    match tagger.get_untagged_images(&service_key) {
-                        Ok(hashes) => {
+
+                    tagger.reload_if_changed();
+
+                    match tagger.service_keys(tag_service).and_then(|services| {
+                        Ok((tagger.get_untagged_images(&services.tags)?, services))
+                    }) {
+                        Ok((hashes, services)) => {
                             if hashes.is_empty() {
                                 info!("Nothing to tag");
                             }
 
-                            hashes.par_iter().for_each(|hash| {
-                                if let Err(e) = tagger.tag_image(&service_key, hash, *dry_run) {
-                                    error!("Error evaluating hash: {:?}", e);
+                            hashes.par_chunks(*batch_size).for_each(|batch| {
+                                if let Err(e) = tagger.tag_images(&services, batch, *dry_run) {
+                                    error!("Error evaluating batch: {:?}", e);
                                 }
                             });
                         }
@@ -156,6 +190,53 @@ impl App {
                     }
                 }
             }
+            Commands::Similar {
+                hash,
+                embedding_store,
+                limit,
+            } => {
+                let embeddings = EmbeddingStore::load(embedding_store)?;
+                for (similar_hash, similarity) in most_similar(&embeddings, hash, *limit)? {
+                    println!("{similar_hash}\t{similarity:.4}");
+                }
+
+                Ok(())
+            }
+            Commands::Cache {
+                command: CacheCommands::Stats { cache_dir },
+            } => {
+                for stats in Cache::new(cache_dir).stats()? {
+                    println!(
+                        "{}\t{} files\t{}",
+                        stats.key,
+                        stats.entries,
+                        HumanBytes(stats.bytes)
+                    );
+                }
+
+                Ok(())
+            }
+            Commands::Cache {
+                command:
+                    CacheCommands::Prune {
+                        cache_dir,
+                        model_dir,
+                    },
+            } => {
+                let keep = model_dir
+                    .iter()
+                    .map(|model_dir| interrogator::cache_key(model_dir))
+                    .collect::<Result<Vec<_>>>()?;
+                let removed = Cache::new(cache_dir).prune(&keep)?;
+                println!(
+                    "Removed {} cached results of {} models, freeing {}",
+                    removed.iter().map(|stats| stats.entries).sum::<usize>(),
+                    removed.len(),
+                    HumanBytes(removed.iter().map(|stats| stats.bytes).sum())
+                );
+
+                Ok(())
+            }
         }
     }
 }
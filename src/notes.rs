@@ -0,0 +1,74 @@
+use anyhow::Result;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::interrogator::Prediction;
+
+/// Settings for writing the scores of a file to a Hydrus note, so results can be audited and
+/// thresholded again without running the model
+#[derive(Debug, Clone)]
+pub struct ScoresNote {
+    /// Name of the note
+    pub name: String,
+    /// Number of tags with the highest confidences to keep, whether they passed their
+    /// threshold or not
+    pub tags: usize,
+}
+
+/// Contents of the note
+#[derive(Debug, Serialize)]
+struct Scores<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratings: Option<&'a IndexMap<String, f32>>,
+    tags: IndexMap<&'a str, f32>,
+}
+
+impl ScoresNote {
+    /// JSON with the model name, all ratings and the top tags, most confident first
+    pub fn render(
+        &self,
+        model: &str,
+        ratings: Option<&IndexMap<String, f32>>,
+        tags: &IndexMap<String, Prediction>,
+    ) -> Result<String> {
+        let mut top: Vec<(&str, f32)> = tags
+            .iter()
+            .map(|(name, prediction)| (name.as_str(), prediction.confidence))
+            .collect();
+        top.sort_by(|a, b| b.1.total_cmp(&a.1));
+        top.truncate(self.tags);
+
+        let scores = Scores {
+            model,
+            ratings,
+            tags: top.into_iter().collect(),
+        };
+        Ok(serde_json::to_string_pretty(&scores)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrogator::predictions;
+
+    #[test]
+    fn test_render() {
+        let note = ScoresNote {
+            name: "ai-tagger scores".to_string(),
+            tags: 2,
+        };
+        let tags = predictions(&[("solo", 0.2), ("1girl", 0.9), ("smile", 0.4)]);
+
+        let rendered = note.render("wd-vit-tagger-v3", None, &tags).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "model": "wd-vit-tagger-v3",
+                "tags": {"1girl": 0.9, "smile": 0.4},
+            })
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{ensure, Context, Result};
+use indexmap::IndexMap;
+use log::{debug, info};
+use regex::RegexSet;
+use serde::Deserialize;
+
+use crate::interrogator::{Prediction, CATEGORY_GENERAL};
+
+/// House tagging conventions applied to the tags that passed their thresholds. Conditional
+/// tags are added based on all of those tags, then the blacklist and whitelist are applied,
+/// and finally aliases rename what is left.
+#[derive(Debug, Default, Clone)]
+pub struct Rules {
+    blacklist: TagMatcher,
+    whitelist: Option<TagMatcher>,
+    aliases: HashMap<String, String>,
+    add: Vec<ConditionalAdd>,
+}
+
+/// Tag names and regular expressions. Names in rules may use spaces or underscores, regular
+/// expressions see the underscores of the model's tag names.
+#[derive(Debug, Default, Clone)]
+struct TagMatcher {
+    names: HashSet<String>,
+    patterns: Option<RegexSet>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConditionalAdd {
+    /// Tags that all have to be present
+    #[serde(rename = "if")]
+    conditions: Vec<String>,
+    /// Tags to add, with the lowest confidence of the conditions
+    then: Vec<String>,
+    #[serde(default)]
+    category: Option<usize>,
+}
+
+/// On-disk form of [`Rules`]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RulesFile {
+    blacklist: Vec<String>,
+    blacklist_regex: Vec<String>,
+    whitelist: Option<Vec<String>>,
+    whitelist_regex: Option<Vec<String>>,
+    aliases: HashMap<String, String>,
+    add: Vec<ConditionalAdd>,
+}
+
+/// Rules may spell tags the way Hydrus shows them
+fn normalize(tag: &str) -> String {
+    tag.replace(' ', "_")
+}
+
+impl TagMatcher {
+    fn new(names: Vec<String>, patterns: Vec<String>) -> Result<Self> {
+        let patterns = match patterns.is_empty() {
+            true => None,
+            false => Some(RegexSet::new(patterns).context("Invalid tag regex")?),
+        };
+
+        Ok(Self {
+            names: names.iter().map(|name| normalize(name)).collect(),
+            patterns,
+        })
+    }
+
+    fn matches(&self, tag: &str) -> bool {
+        self.names.contains(tag)
+            || self
+                .patterns
+                .as_ref()
+                .is_some_and(|patterns| patterns.is_match(tag))
+    }
+}
+
+impl Rules {
+    pub fn load(file: &Path) -> Result<Self> {
+        info!("Loading tag rules from {}", file.display());
+
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Failed reading tag rules from {}", file.display()))?;
+        Self::parse(&contents)
+            .with_context(|| format!("Failed parsing tag rules in {}", file.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(contents)?;
+
+        let whitelist = match (file.whitelist, file.whitelist_regex) {
+            (None, None) => None,
+            (names, patterns) => Some(TagMatcher::new(
+                names.unwrap_or_default(),
+                patterns.unwrap_or_default(),
+            )?),
+        };
+
+        Ok(Self {
+            blacklist: TagMatcher::new(file.blacklist, file.blacklist_regex)?,
+            whitelist,
+            aliases: file
+                .aliases
+                .iter()
+                .map(|(from, to)| (normalize(from), normalize(to)))
+                .collect(),
+            add: file
+                .add
+                .into_iter()
+                .map(|add| {
+                    // Without conditions the tags would be added to every file
+                    ensure!(
+                        !add.conditions.is_empty(),
+                        "Conditional tags {:?} have no if tags",
+                        add.then
+                    );
+                    Ok(ConditionalAdd {
+                        conditions: add.conditions.iter().map(|tag| normalize(tag)).collect(),
+                        then: add.then.iter().map(|tag| normalize(tag)).collect(),
+                        category: add.category,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn apply(&self, tags: IndexMap<String, Prediction>) -> IndexMap<String, Prediction> {
+        let mut added = Vec::new();
+        for add in &self.add {
+            let confidence = add
+                .conditions
+                .iter()
+                .map(|tag| tags.get(tag).map(|prediction| prediction.confidence))
+                .try_fold(f32::MAX, |min, confidence| Some(min.min(confidence?)));
+
+            if let Some(confidence) = confidence {
+                debug!("Adding {:?} because of {:?}", add.then, add.conditions);
+                for tag in &add.then {
+                    let prediction = Prediction {
+                        category: add.category.unwrap_or(CATEGORY_GENERAL),
+                        confidence,
+                    };
+                    added.push((tag.clone(), prediction));
+                }
+            }
+        }
+
+        let mut result = IndexMap::new();
+        let kept = tags.into_iter().chain(added).filter(|(tag, _)| {
+            !self.blacklist.matches(tag)
+                && self
+                    .whitelist
+                    .as_ref()
+                    .map_or(true, |whitelist| whitelist.matches(tag))
+        });
+
+        for (tag, prediction) in kept {
+            let tag = self.aliases.get(&tag).cloned().unwrap_or(tag);
+            result.entry(tag).or_insert(prediction);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrogator::predictions;
+
+    /// Tags with confidences going down from 0.9
+    fn tags(names: &[&str]) -> IndexMap<String, Prediction> {
+        let tags: Vec<(&str, f32)> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (*name, 0.9 - index as f32 * 0.1))
+            .collect();
+        predictions(&tags)
+    }
+
+    fn names(tags: &IndexMap<String, Prediction>) -> Vec<&str> {
+        tags.keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_blacklist() {
+        let rules = Rules::parse(
+            r#"
+            blacklist = ["signature"]
+            blacklist_regex = ["_background$"]
+            "#,
+        )
+        .unwrap();
+
+        let result = rules.apply(tags(&["1girl", "signature", "white_background", "solo"]));
+        assert_eq!(names(&result), vec!["1girl", "solo"]);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let rules = Rules::parse(
+            r#"
+            whitelist = ["cat ears"]
+            whitelist_regex = ["^hair_"]
+            "#,
+        )
+        .unwrap();
+
+        let result = rules.apply(tags(&["1girl", "cat_ears", "hair_ribbon"]));
+        assert_eq!(names(&result), vec!["cat_ears", "hair_ribbon"]);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let rules = Rules::parse(
+            r#"
+            [aliases]
+            1girl = "female"
+            "#,
+        )
+        .unwrap();
+
+        let result = rules.apply(tags(&["1girl", "long_hair"]));
+        assert_eq!(names(&result), vec!["female", "long_hair"]);
+        assert_eq!(result["female"].confidence, 0.9);
+    }
+
+    #[test]
+    fn test_conditional_add() {
+        let rules = Rules::parse(
+            r#"
+            blacklist = ["tail"]
+
+            [[add]]
+            if = ["cat ears", "tail"]
+            then = ["kemonomimi"]
+
+            [[add]]
+            if = ["dog ears"]
+            then = ["inu"]
+            "#,
+        )
+        .unwrap();
+
+        let result = rules.apply(tags(&["cat_ears", "tail", "smile"]));
+        assert_eq!(names(&result), vec!["cat_ears", "smile", "kemonomimi"]);
+        assert!((result["kemonomimi"].confidence - 0.8).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_conditional_add_is_filtered() {
+        let rules = Rules::parse(
+            r#"
+            blacklist = ["kemonomimi"]
+            whitelist_regex = ["ears$", "^kemono", "^animal"]
+
+            [[add]]
+            if = ["cat ears"]
+            then = ["kemonomimi", "animal ears", "cat"]
+            "#,
+        )
+        .unwrap();
+
+        let result = rules.apply(tags(&["cat_ears", "smile"]));
+        assert_eq!(names(&result), vec!["cat_ears", "animal_ears"]);
+    }
+
+    #[test]
+    fn test_conditional_add_without_conditions() {
+        let rules = Rules::parse(
+            r#"
+            [[add]]
+            if = []
+            then = ["kemonomimi"]
+            "#,
+        );
+        assert!(rules.is_err());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(Rules::parse(r#"blacklsit = ["signature"]"#).is_err());
+    }
+
+    #[test]
+    fn test_default_keeps_everything() {
+        let result = Rules::default().apply(tags(&["1girl", "solo"]));
+        assert_eq!(names(&result), vec!["1girl", "solo"]);
+    }
+}
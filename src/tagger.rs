@@ -1,116 +1,747 @@
-use std::path;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use hydrus_api::api_core::{
     common::FileIdentifier,
     endpoints::{
-        adding_tags::AddTagsRequestBuilder,
+        adding_tags::{AddTagsRequestBuilder, TagAction},
         searching_and_fetching_files::{FileSearchOptions, SearchQueryEntry},
     },
 };
-use log::{debug, warn};
+use image::{DynamicImage, GenericImageView};
+use indexmap::{IndexMap, IndexSet};
+use log::{debug, error, warn};
 use rayon::prelude::*;
 use tokio::runtime::Runtime;
 
 use crate::{
-    interrogator::Interrogator,
-    utils::{decode_image, filter_and_process_tags, get_rating},
+    cache::Cache,
+    embeddings::{self, Embedding, EmbeddingStore},
+    ensemble::Ensemble,
+    frames::{self, decode_frames, FrameOptions},
+    interrogator::{to_scores, Interrogation},
+    notes::ScoresNote,
+    rules::Rules,
+    thresholds::Thresholds,
+    utils::{decode_image, filter_and_process_tags, get_rating, top_rating, TagFormat},
 };
 
+/// Which version of a file is downloaded from Hydrus
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Source {
+    /// The original file, falling back to the render if it can't be decoded
+    #[default]
+    Original,
+    /// The file as rendered by Hydrus
+    Render,
+    /// The thumbnail if it is at least as large as the model input, otherwise the original
+    Thumbnail,
+}
+
+/// How tags are written to the tag service
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WriteMode {
+    /// Only add tags, keeping those of earlier runs
+    #[default]
+    Add,
+    /// Also delete the tags in the tag service that weren't predicted this time. Only works on
+    /// local tag services, tag repositories would need petitions.
+    Replace,
+}
+
+/// Storage status of tags that are currently on a file
+const CURRENT_TAGS: &str = "0";
+
+/// Hydrus service types of tag repositories and local tag services
+const TAG_SERVICE_TYPES: &[u64] = &[0, 5];
+/// Hydrus service type of local tag services, the only ones tags can simply be deleted from
+const LOCAL_TAG_SERVICE_TYPES: &[u64] = &[5];
+/// Hydrus service type of numerical rating services
+const RATING_SERVICE_TYPES: &[u64] = &[6];
+
+/// Settings for fetching, tagging and searching files that don't concern the model
+#[derive(Debug, Clone)]
+pub struct TaggerOptions {
+    pub source: Source,
+    pub frames: FrameOptions,
+    /// Search for animated files as well as images
+    pub include_animations: bool,
+    /// Namespace of a tag marking files as processed by a model, which automatic selection
+    /// searches for instead of untagged files
+    pub marker_namespace: Option<String>,
+    /// Select files processed by other models instead of files not processed yet
+    pub retag_older_models: bool,
+    /// Extra Hydrus search predicates for automatic selection
+    pub query: Vec<String>,
+    /// Select files by `query` instead of requiring them to be untagged, markers still apply
+    pub query_only: bool,
+    pub write_mode: WriteMode,
+    /// Applied to the tags that passed their thresholds
+    pub rules: Rules,
+    pub format: TagFormat,
+    pub ratings: RatingOutput,
+    /// Writes the top scores of every tagged file to a note
+    pub scores_note: Option<ScoresNote>,
+    /// Reuses cached scores and adds new ones
+    pub cache: Option<Cache>,
+    /// Saves the embedding of every tagged file. The cache holds no embeddings, so files taken
+    /// from it aren't saved.
+    pub embeddings: Option<Arc<EmbeddingStore>>,
+}
+
+/// Where the rating of a file is written
+#[derive(Debug, Clone, Default)]
+pub enum RatingOutput {
+    /// A `rating:<name>` tag in the tag service
+    #[default]
+    Tag,
+    /// A numerical rating service, with the number of stars for each rating of the model
+    Service {
+        service: String,
+        stars: HashMap<String, u32>,
+    },
+}
+
+/// Keys of the services a tagger writes to
+#[derive(Debug, Clone)]
+pub struct ServiceKeys {
+    pub tags: String,
+    /// Only set with [`RatingOutput::Service`]
+    pub ratings: Option<String>,
+}
+
+/// Results of every model for every frame of a file, and its embedding if it was interrogated
+/// rather than taken from the cache
+type FileResults = (Vec<Vec<Interrogation>>, Option<Embedding>);
+
 pub struct Tagger {
     rt: Arc<Runtime>,
     client: Arc<hydrus_api::Client>,
-    interrogator: Arc<Interrogator>,
-    threshold: f32,
+    ensemble: Arc<Ensemble>,
+    thresholds: Thresholds,
+    options: TaggerOptions,
 }
 
 impl Tagger {
     pub fn new(
         rt: Arc<Runtime>,
         client: Arc<hydrus_api::Client>,
-        model_dir: path::PathBuf,
-        threshold: f32,
-    ) -> Result<Self, Error> {
-        let interrogator = Arc::new(Interrogator::init(&model_dir)?);
-
-        Ok(Self {
+        ensemble: Arc<Ensemble>,
+        thresholds: Thresholds,
+        options: TaggerOptions,
+    ) -> Self {
+        Self {
             rt,
             client,
-            interrogator,
-            threshold,
-        })
+            ensemble,
+            thresholds,
+            options,
+        }
     }
 
-    pub fn tag_image(&self, service_key: &str, hash: &str, dry_run: bool) -> Result<Vec<String>> {
-        debug!("Tagging {}", hash);
+    /// Loads models that changed on disk again, keeping the old ones if that fails
+    pub fn reload_if_changed(&mut self) {
+        match self.ensemble.reload_if_changed() {
+            Ok(Some(reloaded)) => self.ensemble = Arc::new(reloaded),
+            Ok(None) => {}
+            Err(e) => error!("Failed reloading model, keeping old one: {:?}", e),
+        }
+    }
 
-        let record = self
-            .rt
-            .block_on(self.client.get_file(FileIdentifier::hash(hash)))
-            .context("Error getting image file from Hydrus API")?;
+    /// Downloads all `hashes` and interrogates them as a single batch, which holds several
+    /// frames for animations. Files that fail to download, decode or be written to Hydrus are
+    /// logged and skipped, so they return `None` without holding up the rest of the batch.
+    pub fn tag_images(
+        &self,
+        services: &ServiceKeys,
+        hashes: &[String],
+        dry_run: bool,
+    ) -> Result<Vec<Option<Vec<String>>>> {
+        debug!("Tagging batch of {}", hashes.len());
 
-        let image = decode_image(&record.bytes)
-            .or_else(|_| {
-                warn!("Failed decoding original image, falling back to using hydrus render");
-                let rendered = self
-                    .rt
-                    .block_on(self.client.get_render(FileIdentifier::hash(hash)))
-                    .context("Error rendering file")?;
-                decode_image(&rendered.bytes)
+        let results = self.interrogate(hashes)?;
+        let current_tags = match self.options.write_mode {
+            WriteMode::Add => None,
+            WriteMode::Replace => Some(self.current_tags(&services.tags, hashes)?),
+        };
+
+        Ok(hashes
+            .iter()
+            .zip(results)
+            .map(|(hash, results)| {
+                let (frames, embedding) = results?;
+                let result = self.tag_image(
+                    services,
+                    hash,
+                    frames,
+                    embedding,
+                    current_tags.as_ref(),
+                    dry_run,
+                );
+                result
+                    .map_err(|e| error!("Failed tagging {}: {:?}", hash, e))
+                    .ok()
             })
-            .context("Failed to decode image")?;
+            .collect())
+    }
 
-        let (ratings, tags) = self
-            .interrogator
-            .interrogate(&image)
-            .context("Failed interrogating model")?;
+    /// Turns the results of one file into tags and writes them, along with its rating, note
+    /// and embedding
+    fn tag_image(
+        &self,
+        services: &ServiceKeys,
+        hash: &str,
+        frames: Vec<Vec<Interrogation>>,
+        embedding: Option<Embedding>,
+        current_tags: Option<&HashMap<String, Vec<String>>>,
+        dry_run: bool,
+    ) -> Result<Vec<String>> {
+        let frames: Vec<Interrogation> = frames
+            .into_iter()
+            .map(|results| self.ensemble.combine(results))
+            .collect();
+        // Taken before aggregating, which can drop tags that only failed their threshold
+        let note_tags = self
+            .options
+            .scores_note
+            .as_ref()
+            .map(|_| frames::max_tag_scores(&frames));
+        let (ratings, tags) = frames::aggregate(frames, &self.options.frames, &self.thresholds);
 
-        let mut filtered_tags = filter_and_process_tags(tags, self.threshold);
+        if let (Some(store), Some(embedding)) = (&self.options.embeddings, embedding) {
+            store.add(hash, &embedding)?;
+        }
+
+        let note = self
+            .options
+            .scores_note
+            .as_ref()
+            .zip(note_tags)
+            .map(|(note, note_tags)| {
+                let text = note.render(&self.ensemble.name(), ratings.as_ref(), &note_tags)?;
+                Ok::<_, anyhow::Error>((note.name.clone(), text))
+            })
+            .transpose()?;
+
+        let mut filtered_tags = filter_and_process_tags(
+            tags,
+            &self.thresholds,
+            &self.options.rules,
+            &self.options.format,
+            self.ensemble.namespaces(),
+        );
+
+        if let Some(marker) = self.marker() {
+            filtered_tags.push(marker);
+        }
 
         if let Some(ratings) = ratings {
-            filtered_tags.push(get_rating(&ratings)?);
+            match (&self.options.ratings, &services.ratings) {
+                (RatingOutput::Service { stars, .. }, Some(service_key)) => {
+                    self.set_rating(hash, service_key, stars, &ratings, dry_run)?
+                }
+                _ => filtered_tags.push(get_rating(&ratings)?),
+            }
         }
 
-        let request = AddTagsRequestBuilder::default()
-            .add_hash(hash)
-            .add_tags(service_key.to_string(), filtered_tags.clone())
-            .build();
+        let (added, deleted) = match current_tags {
+            Some(current_tags) => {
+                let current = current_tags.get(hash).map_or(&[][..], Vec::as_slice);
+                diff_tags(current, &filtered_tags)
+            }
+            None => (filtered_tags.clone(), Vec::new()),
+        };
 
-        debug!("Tags to be added: {:?}", request.service_keys_to_tags);
+        debug!("Tags to be added: {:?}, deleted: {:?}", added, deleted);
+
+        if dry_run && current_tags.is_some() {
+            print_diff(hash, &added, &deleted);
+        }
+
+        if !dry_run && (!added.is_empty() || !deleted.is_empty()) {
+            let request = deleted
+                .into_iter()
+                .fold(
+                    AddTagsRequestBuilder::default()
+                        .add_hash(hash)
+                        .add_tags(services.tags.clone(), added),
+                    |request, tag| {
+                        request.add_tag_with_action(
+                            services.tags.clone(),
+                            tag,
+                            TagAction::DeleteFromLocalService,
+                        )
+                    },
+                )
+                .build();
 
-        if !dry_run {
             self.rt
                 .block_on(self.client.add_tags(request))
                 .context("Failed adding tags")?;
         }
 
+        if let Some((name, text)) = note {
+            debug!("Note {} to be set: {}", name, text);
+
+            if !dry_run {
+                self.rt
+                    .block_on(
+                        self.client
+                            .set_notes(FileIdentifier::hash(hash), HashMap::from([(name, text)])),
+                    )
+                    .context("Failed setting scores note")?;
+            }
+        }
+
         Ok(filtered_tags)
     }
 
+    /// Tags currently on each of `hashes` in the tag service
+    fn current_tags(
+        &self,
+        service_key: &str,
+        hashes: &[String],
+    ) -> Result<HashMap<String, Vec<String>>> {
+        let metadata = self
+            .rt
+            .block_on(self.client.get_file_metadata(Vec::new(), hashes.to_vec()))
+            .context("Failed getting file metadata")?
+            .metadata;
+
+        Ok(metadata
+            .into_iter()
+            .map(|mut file| {
+                let tags = file
+                    .tags
+                    .remove(service_key)
+                    .and_then(|mut tags| tags.storage_tags.remove(CURRENT_TAGS))
+                    .unwrap_or_default();
+                (file.hash, tags)
+            })
+            .collect())
+    }
+
+    fn set_rating(
+        &self,
+        hash: &str,
+        service_key: &str,
+        stars: &HashMap<String, u32>,
+        ratings: &IndexMap<String, f32>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let rating = top_rating(ratings)?;
+        let Some(&stars) = stars.get(rating) else {
+            warn!(
+                "No number of stars set for rating {}, not rating {}",
+                rating, hash
+            );
+            return Ok(());
+        };
+
+        debug!("Rating to be set: {} ({} stars)", rating, stars);
+
+        if !dry_run {
+            self.rt
+                .block_on(self.client.set_rating(
+                    FileIdentifier::hash(hash),
+                    service_key.to_string(),
+                    Some(stars),
+                ))
+                .context("Failed setting rating")?;
+        }
+
+        Ok(())
+    }
+
+    /// Results of every model for every frame of each file, or `None` if it couldn't be
+    /// fetched. Files that all models scored before are taken from the cache, the others are
+    /// fetched and interrogated as one batch and also return their embedding.
+    fn interrogate(&self, hashes: &[String]) -> Result<Vec<Option<FileResults>>> {
+        let cached: Vec<Option<Vec<Vec<Interrogation>>>> =
+            hashes.iter().map(|hash| self.cached(hash)).collect();
+        let missing: Vec<&String> = hashes
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(hash, _)| hash)
+            .collect();
+
+        let frames: Vec<Option<Vec<DynamicImage>>> = missing
+            .par_iter()
+            .map(|hash| {
+                self.fetch_frames(hash)
+                    .map_err(|e| error!("Failed fetching {}, skipping it: {:?}", hash, e))
+                    .ok()
+            })
+            .collect();
+        let frame_counts: Vec<Option<usize>> = frames
+            .iter()
+            .map(|frames| frames.as_ref().map(Vec::len))
+            .collect();
+        let images: Vec<DynamicImage> = frames.into_iter().flatten().flatten().collect();
+
+        let mut results = self
+            .ensemble
+            .interrogate_batch(&images)
+            .context("Failed interrogating model")?
+            .into_iter();
+
+        let mut fresh = Vec::with_capacity(missing.len());
+        for (hash, frame_count) in missing.into_iter().zip(frame_counts) {
+            let Some(frame_count) = frame_count else {
+                fresh.push(None);
+                continue;
+            };
+
+            let (frames, embeddings): (Vec<_>, Vec<_>) = results.by_ref().take(frame_count).unzip();
+            if let Err(e) = self.cache_results(hash, &frames) {
+                warn!("Failed caching scores of {}: {:?}", hash, e);
+            }
+            fresh.push(Some((
+                frames,
+                embeddings::mean(embeddings.into_iter().flatten()),
+            )));
+        }
+        let mut fresh = fresh.into_iter();
+
+        cached
+            .into_iter()
+            .map(|cached| match cached {
+                Some(frames) => Some(Some((frames, None))),
+                None => fresh.next(),
+            })
+            .collect::<Option<_>>()
+            .context("Missing interrogation results")
+    }
+
+    /// Cached results of every model for every frame, if all models scored the file before
+    fn cached(&self, hash: &str) -> Option<Vec<Vec<Interrogation>>> {
+        let cache = self.options.cache.as_ref()?;
+        let cached = || -> Result<Option<Vec<Vec<Interrogation>>>> {
+            let mut per_model = Vec::new();
+            for key in self.ensemble.cache_keys()? {
+                match cache.get(key, hash)? {
+                    Some(frames) => per_model.push(frames),
+                    None => return Ok(None),
+                }
+            }
+
+            let frame_count = per_model[0].len();
+            if per_model.iter().any(|frames| frames.len() != frame_count) {
+                return Ok(None);
+            }
+
+            (0..frame_count)
+                .map(|frame| {
+                    per_model
+                        .iter()
+                        .enumerate()
+                        .map(|(index, frames)| self.ensemble.decode_scores(index, &frames[frame]))
+                        .collect()
+                })
+                .collect::<Result<_>>()
+                .map(Some)
+        };
+
+        cached().unwrap_or_else(|e| {
+            warn!("Ignoring cached scores of {}: {:?}", hash, e);
+            None
+        })
+    }
+
+    fn cache_results(&self, hash: &str, frames: &[Vec<Interrogation>]) -> Result<()> {
+        let Some(cache) = &self.options.cache else {
+            return Ok(());
+        };
+
+        for (index, key) in self.ensemble.cache_keys()?.into_iter().enumerate() {
+            let scores: Vec<Vec<f32>> = frames
+                .iter()
+                .map(|results| to_scores(&results[index]))
+                .collect();
+            cache.put(key, hash, &scores)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches a file and decodes its sampled frames, or just the image if it isn't animated
+    fn fetch_frames(&self, hash: &str) -> Result<Vec<DynamicImage>> {
+        match self.options.source {
+            Source::Original => {}
+            Source::Render => return self.fetch_render(hash).map(|image| vec![image]),
+            Source::Thumbnail => match self.fetch_thumbnail(hash) {
+                Ok(Some(thumbnail)) => return Ok(vec![thumbnail]),
+                Ok(None) => debug!("Thumbnail of {} is too small, using the original", hash),
+                Err(e) => warn!(
+                    "Failed using thumbnail of {}, using the original: {:?}",
+                    hash, e
+                ),
+            },
+        }
+
+        debug!("Fetching {}", hash);
+
+        let record = self
+            .rt
+            .block_on(self.client.get_file(FileIdentifier::hash(hash)))
+            .context("Error getting image file from Hydrus API")?;
+
+        decode_frames(&record.bytes, self.options.frames.count)
+            .or_else(|_| {
+                warn!("Failed decoding original image, falling back to using hydrus render");
+                self.fetch_render(hash).map(|image| vec![image])
+            })
+            .context("Failed to decode image")
+    }
+
+    fn fetch_render(&self, hash: &str) -> Result<DynamicImage> {
+        let rendered = self
+            .rt
+            .block_on(self.client.get_render(FileIdentifier::hash(hash)))
+            .context("Error rendering file")?;
+        decode_image(&rendered.bytes)
+    }
+
+    /// Fetches the thumbnail, or `None` if its shortest side is smaller than the model input
+    fn fetch_thumbnail(&self, hash: &str) -> Result<Option<DynamicImage>> {
+        let record = self
+            .rt
+            .block_on(self.client.get_thumbnail(FileIdentifier::hash(hash)))
+            .context("Error getting thumbnail")?;
+        let thumbnail = decode_image(&record.bytes)?;
+        let (width, height) = thumbnail.dimensions();
+        let input_size = self.ensemble.input_size()?;
+
+        Ok((width.min(height) as usize >= input_size).then_some(thumbnail))
+    }
+
+    /// Tag marking files as processed by the current models, such as
+    /// `ai-tagger:wd-vit-tagger-v3`
+    fn marker(&self) -> Option<String> {
+        let namespace = self.options.marker_namespace.as_ref()?;
+        Some(format!(
+            "{}:{}",
+            namespace,
+            self.ensemble.name().to_lowercase()
+        ))
+    }
+
+    /// Files without tags, or without the marker of the current models if markers are used
     pub fn get_untagged_images(&self, service_key: &str) -> Result<Vec<String>> {
+        let predicates = search_predicates(&self.options, self.marker().as_deref())
+            .into_iter()
+            .map(SearchQueryEntry::Tag)
+            .collect();
         let hashes = self
             .rt
             .block_on(self.client.search_file_hashes(
-                vec![
-                    SearchQueryEntry::Tag(String::from("system:untagged")),
-                    SearchQueryEntry::Tag(String::from("system:filetype is image")),
-                ],
+                predicates,
                 FileSearchOptions::new().tag_service_key(service_key.to_string()),
             ))?
             .hashes;
         Ok(hashes)
     }
 
-    pub fn get_tag_service_key_from_name(&self, tag_service: &String) -> Result<String> {
-        let service_key = self
-            .rt
-            .block_on(self.client.get_services())?
-            .services
+    /// Looks up the keys of the tag service and of the rating service, if ratings go to one
+    pub fn service_keys(&self, tag_service: &str) -> Result<ServiceKeys> {
+        let ratings = match &self.options.ratings {
+            RatingOutput::Tag => None,
+            RatingOutput::Service { service, .. } => {
+                Some(self.get_service_key(service, "numerical rating", RATING_SERVICE_TYPES)?)
+            }
+        };
+
+        let tags = match self.options.write_mode {
+            WriteMode::Add => self.get_service_key(tag_service, "tag", TAG_SERVICE_TYPES)?,
+            // Tags in tag repositories need petitions to be deleted
+            WriteMode::Replace => {
+                self.get_service_key(tag_service, "local tag", LOCAL_TAG_SERVICE_TYPES)?
+            }
+        };
+
+        Ok(ServiceKeys { tags, ratings })
+    }
+
+    /// Key of the service called `name`, which has to be of one of `service_types`
+    fn get_service_key(&self, name: &str, kind: &str, service_types: &[u64]) -> Result<String> {
+        let services = self.rt.block_on(self.client.get_services())?.services;
+        let (service_key, service) = services
             .par_iter()
-            .find_any(|x| x.1.name == *tag_service)
-            .map(|x| x.0.to_owned())
-            .ok_or(anyhow!("Could not find tag service {}", tag_service))?;
-        Ok(service_key)
+            .find_any(|x| x.1.name == name)
+            .ok_or(anyhow!("Could not find {} service {}", kind, name))?;
+        if !service_types.contains(&service.service_type) {
+            return Err(anyhow!("Service {} is not a {} service", name, kind));
+        }
+        Ok(service_key.to_owned())
+    }
+}
+
+/// Tags to add and to delete to turn `current` into `new`. Tags are compared the way Hydrus
+/// stores them, lowercase and trimmed, and added in that form.
+fn diff_tags(current: &[String], new: &[String]) -> (Vec<String>, Vec<String>) {
+    let normalize = |tag: &String| tag.trim().to_lowercase();
+    let current_normalized: Vec<String> = current.iter().map(normalize).collect();
+    let new: IndexSet<String> = new.iter().map(normalize).collect();
+
+    let added = new
+        .iter()
+        .filter(|tag| !current_normalized.contains(tag))
+        .cloned()
+        .collect();
+    let deleted = current
+        .iter()
+        .zip(&current_normalized)
+        .filter(|(_, normalized)| !new.contains(*normalized))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+    (added, deleted)
+}
+
+/// Prints the changes to the tags of a file, if there are any
+fn print_diff(hash: &str, added: &[String], deleted: &[String]) {
+    if added.is_empty() && deleted.is_empty() {
+        return;
+    }
+
+    let mut diff = String::from(hash);
+    for tag in added {
+        diff.push_str(&format!("\n  + {tag}"));
+    }
+    for tag in deleted {
+        diff.push_str(&format!("\n  - {tag}"));
+    }
+    println!("{diff}");
+}
+
+/// Predicates for automatically selecting files
+fn search_predicates(options: &TaggerOptions, marker: Option<&str>) -> Vec<String> {
+    let filetype = match options.include_animations {
+        true => "system:filetype is image, animation",
+        false => "system:filetype is image",
+    };
+
+    // --query-only replaces system:untagged, markers are still needed to not tag files again
+    let mut predicates = match (&options.marker_namespace, marker) {
+        (Some(namespace), Some(marker)) if options.retag_older_models => {
+            vec![format!("{namespace}:*"), format!("-{marker}")]
+        }
+        (Some(namespace), _) => vec![format!("-{namespace}:*")],
+        _ if options.query_only => Vec::new(),
+        _ => vec![String::from("system:untagged")],
+    };
+    predicates.push(String::from(filetype));
+    predicates.extend(options.query.iter().cloned());
+    predicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::FrameAggregation;
+
+    fn options(marker_namespace: Option<&str>, retag_older_models: bool) -> TaggerOptions {
+        TaggerOptions {
+            source: Source::Original,
+            frames: FrameOptions {
+                count: 1,
+                aggregation: FrameAggregation::Max,
+                min_frequency: 0.5,
+            },
+            include_animations: false,
+            marker_namespace: marker_namespace.map(String::from),
+            retag_older_models,
+            query: Vec::new(),
+            query_only: false,
+            write_mode: WriteMode::Add,
+            rules: Rules::default(),
+            format: TagFormat::default(),
+            ratings: RatingOutput::Tag,
+            scores_note: None,
+            cache: None,
+            embeddings: None,
+        }
+    }
+
+    fn strings(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_diff_tags() {
+        let current = strings(&["1girl", "smile", "ai-tagger:wd-v1-4-vit-tagger-v2"]);
+        let new = strings(&["1girl", "long hair", "ai-tagger:wd-vit-tagger-v3"]);
+
+        let (added, deleted) = diff_tags(&current, &new);
+        assert_eq!(added, strings(&["long hair", "ai-tagger:wd-vit-tagger-v3"]));
+        assert_eq!(
+            deleted,
+            strings(&["smile", "ai-tagger:wd-v1-4-vit-tagger-v2"])
+        );
+    }
+
+    #[test]
+    fn test_diff_tags_case() {
+        let (added, deleted) = diff_tags(&strings(&["female"]), &strings(&["Female ", "Solo"]));
+        assert_eq!(added, strings(&["solo"]));
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn test_search_predicates() {
+        assert_eq!(
+            search_predicates(&options(None, false), None),
+            vec!["system:untagged", "system:filetype is image"]
+        );
+
+        let marker = Some("ai-tagger:wd-vit-tagger-v3");
+        assert_eq!(
+            search_predicates(&options(Some("ai-tagger"), false), marker),
+            vec!["-ai-tagger:*", "system:filetype is image"]
+        );
+        assert_eq!(
+            search_predicates(&options(Some("ai-tagger"), true), marker),
+            vec![
+                "ai-tagger:*",
+                "-ai-tagger:wd-vit-tagger-v3",
+                "system:filetype is image"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_predicates_query() {
+        let mut options = options(None, false);
+        options.query = vec![String::from("system:import time < 7 days")];
+        assert_eq!(
+            search_predicates(&options, None),
+            vec![
+                "system:untagged",
+                "system:filetype is image",
+                "system:import time < 7 days"
+            ]
+        );
+
+        options.query_only = true;
+        assert_eq!(
+            search_predicates(&options, None),
+            vec!["system:filetype is image", "system:import time < 7 days"]
+        );
+    }
+
+    #[test]
+    fn test_search_predicates_query_only_with_marker() {
+        let mut options = options(Some("ai-tagger"), false);
+        options.query = vec![String::from("system:import time < 7 days")];
+        options.query_only = true;
+        assert_eq!(
+            search_predicates(&options, Some("ai-tagger:wd-vit-tagger-v3")),
+            vec![
+                "-ai-tagger:*",
+                "system:filetype is image",
+                "system:import time < 7 days"
+            ]
+        );
     }
 }
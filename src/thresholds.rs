@@ -0,0 +1,220 @@
+use std::{borrow::Cow, collections::HashMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::interrogator::{Prediction, CATEGORY_CHARACTER, CATEGORY_GENERAL};
+
+/// Name of the threshold table that is picked up from the model folder
+const THRESHOLDS_FILE: &str = "thresholds.json";
+
+/// Lowest threshold MCut may pick for character tags, so a lone weak character guess is dropped
+const MCUT_CHARACTER_MIN: f32 = 0.15;
+
+/// How the threshold for each tag is picked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ThresholdMode {
+    /// Use the configured thresholds as they are
+    #[default]
+    Fixed,
+    /// Maximum Cut Thresholding: cut general and character tags at the largest gap between
+    /// their sorted scores
+    Mcut,
+}
+
+/// Confidence a tag needs to be used, with overrides per category and per tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    mode: ThresholdMode,
+    default: f32,
+    categories: HashMap<usize, f32>,
+    tags: HashMap<String, f32>,
+}
+
+/// On-disk form of [`Thresholds`] where the default may be left out
+#[derive(Debug, Deserialize)]
+struct ThresholdsFile {
+    default: Option<f32>,
+    #[serde(default)]
+    categories: HashMap<usize, f32>,
+    #[serde(default)]
+    tags: HashMap<String, f32>,
+}
+
+impl Thresholds {
+    pub fn new(default: f32) -> Self {
+        Self {
+            mode: ThresholdMode::default(),
+            default,
+            categories: HashMap::new(),
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Loads the threshold table from `file`, or from the model folder if no file is given.
+    /// `default` is used for tags without an override when the table doesn't set one.
+    pub fn load(file: Option<&Path>, model_dir: &Path, default: f32) -> Result<Self> {
+        let model_file = model_dir.join(THRESHOLDS_FILE);
+        let file = match file {
+            Some(file) => file,
+            None if model_file.is_file() => &model_file,
+            None => return Ok(Self::new(default)),
+        };
+
+        info!("Loading thresholds from {}", file.display());
+
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Failed reading thresholds from {}", file.display()))?;
+        let table: ThresholdsFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed parsing thresholds in {}", file.display()))?;
+
+        Ok(Self {
+            mode: ThresholdMode::default(),
+            default: table.default.unwrap_or(default),
+            categories: table.categories,
+            tags: table.tags,
+        })
+    }
+
+    pub fn with_mode(self, mode: ThresholdMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    /// Thresholds to apply to one image's predictions. In MCut mode the general and character
+    /// category thresholds are computed from the predictions, tag overrides still apply.
+    pub fn for_predictions(&self, tags: &IndexMap<String, Prediction>) -> Cow<'_, Self> {
+        match self.mode {
+            ThresholdMode::Fixed => Cow::Borrowed(self),
+            ThresholdMode::Mcut => {
+                let mut thresholds = self.clone();
+
+                for (category, min) in [
+                    (CATEGORY_GENERAL, 0.0),
+                    (CATEGORY_CHARACTER, MCUT_CHARACTER_MIN),
+                ] {
+                    let scores = tags
+                        .values()
+                        .filter(|prediction| prediction.category == category)
+                        .map(|prediction| prediction.confidence)
+                        .collect();
+
+                    if let Some(threshold) = mcut(scores) {
+                        debug!("MCut threshold for category {}: {}", category, threshold);
+                        thresholds.categories.insert(category, threshold.max(min));
+                    }
+                }
+
+                Cow::Owned(thresholds)
+            }
+        }
+    }
+
+    /// Threshold for a tag, preferring a tag override over a category override
+    pub fn get(&self, tag: &str, category: usize) -> f32 {
+        self.tags
+            .get(tag)
+            .or_else(|| self.categories.get(&category))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Maximum Cut Thresholding: sorts the scores and returns the midpoint of the largest gap
+/// between neighbours, or `None` if there are fewer than two scores
+fn mcut(mut scores: Vec<f32>) -> Option<f32> {
+    scores.sort_by(|a, b| b.total_cmp(a));
+    scores
+        .windows(2)
+        .max_by(|a, b| (a[0] - a[1]).total_cmp(&(b[0] - b[1])))
+        .map(|pair| (pair[0] + pair[1]) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let thresholds = Thresholds {
+            mode: ThresholdMode::Fixed,
+            default: 0.35,
+            categories: HashMap::from([(4, 0.85)]),
+            tags: HashMap::from([("solo".to_string(), 0.6), ("hatsune_miku".to_string(), 0.5)]),
+        };
+
+        assert_eq!(thresholds.get("1girl", 0), 0.35);
+        assert_eq!(thresholds.get("solo", 0), 0.6);
+        assert_eq!(thresholds.get("kagamine_rin", 4), 0.85);
+        assert_eq!(thresholds.get("hatsune_miku", 4), 0.5);
+    }
+
+    #[test]
+    fn test_mcut() {
+        let threshold = mcut(vec![0.1, 0.9, 0.8, 0.15]).unwrap();
+        assert!((threshold - 0.475).abs() < f32::EPSILON);
+        assert_eq!(mcut(vec![0.5]), None);
+    }
+
+    #[test]
+    fn test_for_predictions_mcut() {
+        let mut tags = IndexMap::new();
+        for (tag, category, confidence) in [
+            ("1girl", CATEGORY_GENERAL, 0.95),
+            ("solo", CATEGORY_GENERAL, 0.9),
+            ("long_hair", CATEGORY_GENERAL, 0.3),
+            ("hatsune_miku", CATEGORY_CHARACTER, 0.12),
+            ("kagamine_rin", CATEGORY_CHARACTER, 0.02),
+        ] {
+            tags.insert(
+                tag.to_string(),
+                Prediction {
+                    category,
+                    confidence,
+                },
+            );
+        }
+
+        let thresholds = Thresholds::new(0.35).with_mode(ThresholdMode::Mcut);
+        let thresholds = thresholds.for_predictions(&tags);
+        assert!((thresholds.get("long_hair", CATEGORY_GENERAL) - 0.6).abs() < f32::EPSILON);
+        assert_eq!(
+            thresholds.get("hatsune_miku", CATEGORY_CHARACTER),
+            MCUT_CHARACTER_MIN
+        );
+    }
+
+    #[test]
+    fn test_load_from_model_dir() {
+        let model_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            model_dir.path().join(THRESHOLDS_FILE),
+            r#"{"categories": {"4": 0.85}, "tags": {"solo": 0.6}}"#,
+        )
+        .unwrap();
+
+        let thresholds = Thresholds::load(None, model_dir.path(), 0.35).unwrap();
+        assert_eq!(thresholds.get("1girl", 0), 0.35);
+        assert_eq!(thresholds.get("kagamine_rin", 4), 0.85);
+        assert_eq!(thresholds.get("solo", 0), 0.6);
+    }
+
+    #[test]
+    fn test_load_explicit_file() {
+        let model_dir = tempfile::tempdir().unwrap();
+        let file = model_dir.path().join("custom.json");
+        fs::write(&file, r#"{"default": 0.5}"#).unwrap();
+
+        let thresholds = Thresholds::load(Some(&file), model_dir.path(), 0.35).unwrap();
+        assert_eq!(thresholds.get("1girl", 0), 0.5);
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let model_dir = tempfile::tempdir().unwrap();
+        let thresholds = Thresholds::load(None, model_dir.path(), 0.35).unwrap();
+        assert_eq!(thresholds, Thresholds::new(0.35));
+    }
+}
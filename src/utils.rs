@@ -1,28 +1,143 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs,
     io::{self, BufRead},
-    path,
+    path::{self, Path},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::ValueEnum;
 use image::{DynamicImage, ImageReader};
 use indexmap::IndexMap;
 use rayon::prelude::*;
 
+use crate::{interrogator::Prediction, rules::Rules, thresholds::Thresholds};
+
 /// Kaomoji tags to be excluded from the process of replacing '_' with space
 const KAOMOJIS: &[&str] = &[
     "0_0", "(o)_(o)", "+_+", "+_-", "._.", "<o>_<o>", "<|>_<|>", "=_=", ">_<", "3_3", "6_9", ">_o",
     "@_@", "^_^", "o_o", "u_u", "x_x", "|_|", "||_||",
 ];
 
+/// What happens to the underscores in tag names
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnderscorePolicy {
+    /// Replace them with spaces
+    #[default]
+    Replace,
+    /// Keep Danbooru-style underscores
+    Keep,
+    /// Replace them with --underscore-replacement
+    Custom,
+}
+
+/// How tag names are written to the tag service
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagFormat {
+    /// `None` keeps underscores
+    replacement: Option<String>,
+    /// Tags whose underscores are always kept, such as kaomoji
+    exceptions: HashSet<String>,
+}
+
+impl Default for TagFormat {
+    fn default() -> Self {
+        Self {
+            replacement: Some(" ".to_string()),
+            exceptions: KAOMOJIS.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl TagFormat {
+    /// `exceptions_file` lists one tag per line and replaces the built-in kaomoji list
+    pub fn new(
+        policy: UnderscorePolicy,
+        replacement: Option<&str>,
+        exceptions_file: Option<&Path>,
+    ) -> Result<Self> {
+        let replacement = match policy {
+            UnderscorePolicy::Replace => Some(" ".to_string()),
+            UnderscorePolicy::Keep => None,
+            UnderscorePolicy::Custom => Some(
+                replacement
+                    .context("The custom underscore policy needs a replacement")?
+                    .to_string(),
+            ),
+        };
+
+        let exceptions = match exceptions_file {
+            Some(file) => fs::read_to_string(file)
+                .with_context(|| format!("Failed reading {}", file.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+            None => Self::default().exceptions,
+        };
+
+        Ok(Self {
+            replacement,
+            exceptions,
+        })
+    }
+
+    fn format(&self, tag: String) -> String {
+        match &self.replacement {
+            Some(replacement) if !self.exceptions.contains(&tag) => tag.replace('_', replacement),
+            _ => tag,
+        }
+    }
+}
+
 pub fn get_rating(ratings: &IndexMap<String, f32>) -> Result<String> {
+    top_rating(ratings).map(|r| format!("rating:{r}"))
+}
+
+/// Name of the rating with the highest score
+pub fn top_rating(ratings: &IndexMap<String, f32>) -> Result<&str> {
     ratings
         .par_iter()
         .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
-        .map(|(r, _)| format!("rating:{r}"))
+        .map(|(r, _)| r.as_str())
         .ok_or_else(|| anyhow!("Ratings was empty"))
 }
 
+/// Parses an aspect ratio, which is at least 1
+pub fn parse_aspect_ratio(value: &str) -> Result<f32> {
+    let ratio: f32 = value.parse()?;
+    ensure!(
+        ratio.is_finite() && ratio >= 1.0,
+        "Aspect ratio must be at least 1"
+    );
+    Ok(ratio)
+}
+
+/// Parses a fraction between 0 and `max`
+pub fn parse_fraction(max: f32) -> impl Fn(&str) -> Result<f32> + Clone + Send + Sync {
+    move |value| {
+        let fraction: f32 = value.parse()?;
+        ensure!(
+            (0.0..=max).contains(&fraction),
+            "Must be between 0 and {max}"
+        );
+        Ok(fraction)
+    }
+}
+
+/// Parses a `rating=stars` pair of the mapping onto a numerical rating service
+pub fn parse_rating_stars(mapping: &str) -> Result<(String, u32)> {
+    let (rating, stars) = mapping
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <rating>=<stars>, got {mapping}"))?;
+    let stars = stars
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid number of stars for {rating}"))?;
+    Ok((rating.trim().to_string(), stars))
+}
+
 pub fn parse_hashes_file(path: &path::PathBuf) -> Result<Vec<String>> {
     let bytes = fs::read(path)?;
     let lines = bytes.lines().collect::<io::Result<Vec<String>>>()?;
@@ -30,16 +145,29 @@ pub fn parse_hashes_file(path: &path::PathBuf) -> Result<Vec<String>> {
 }
 
 pub fn filter_and_process_tags(
-    tags: indexmap::IndexMap<String, f32>,
-    threshold: f32,
+    tags: indexmap::IndexMap<String, Prediction>,
+    thresholds: &Thresholds,
+    rules: &Rules,
+    format: &TagFormat,
+    namespaces: &HashMap<usize, String>,
 ) -> Vec<String> {
-    tags.into_par_iter()
-        .filter(|(_, confidence)| *confidence > threshold)
-        .map(|(tag, _)| {
-            if KAOMOJIS.contains(&tag.as_str()) {
-                tag
-            } else {
-                tag.replace('_', " ")
+    let thresholds = thresholds.for_predictions(&tags);
+    let tags = tags
+        .into_iter()
+        .filter(|(tag, prediction)| {
+            prediction.confidence > thresholds.get(tag, prediction.category)
+        })
+        .collect();
+
+    rules
+        .apply(tags)
+        .into_par_iter()
+        .map(|(tag, prediction)| {
+            let tag = format.format(tag);
+
+            match namespaces.get(&prediction.category) {
+                Some(namespace) => format!("{namespace}:{tag}"),
+                None => tag,
             }
         })
         .collect()
@@ -54,6 +182,7 @@ pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::interrogator::predictions;
 
     #[test]
     fn test_get_rating() {
@@ -73,6 +202,32 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_aspect_ratio() {
+        assert_eq!(parse_aspect_ratio("2.5").unwrap(), 2.5);
+        assert!(parse_aspect_ratio("0.5").is_err());
+        assert!(parse_aspect_ratio("inf").is_err());
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        let parse = parse_fraction(0.9);
+        assert_eq!(parse("0.25").unwrap(), 0.25);
+        assert!(parse("0.95").is_err());
+        assert!(parse("-0.1").is_err());
+        assert!(parse("NaN").is_err());
+    }
+
+    #[test]
+    fn test_parse_rating_stars() {
+        assert_eq!(
+            parse_rating_stars("explicit=4").unwrap(),
+            ("explicit".to_string(), 4)
+        );
+        assert!(parse_rating_stars("explicit").is_err());
+        assert!(parse_rating_stars("explicit=many").is_err());
+    }
+
     #[test]
     fn test_parse_hashes_file() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -98,16 +253,69 @@ a74b62685203edd41fb684a21377aa1fc2d699d2e11b8aab14d683c5505fce69",
 
     #[test]
     fn test_filter_and_process_tags() {
-        let mut tags = IndexMap::new();
-        tags.insert("tag_one".to_string(), 0.9);
-        tags.insert("tag_two".to_string(), 0.7);
-        tags.insert("0_0".to_string(), 0.8);
-        tags.insert("low_confidence".to_string(), 0.3);
+        let tags = predictions(&[
+            ("tag_one", 0.9),
+            ("tag_two", 0.7),
+            ("0_0", 0.8),
+            ("low_confidence", 0.3),
+        ]);
 
-        let result = filter_and_process_tags(tags, 0.5);
+        let result = filter_and_process_tags(
+            tags,
+            &Thresholds::new(0.5),
+            &Rules::default(),
+            &TagFormat::default(),
+            &HashMap::new(),
+        );
         assert_eq!(result, vec!["tag one", "tag two", "0_0"]);
     }
 
+    #[test]
+    fn test_filter_and_process_tags_namespaces() {
+        let mut tags = predictions(&[("1girl", 0.9)]);
+        tags.insert(
+            "hatsune_miku".to_string(),
+            Prediction {
+                category: 4,
+                confidence: 0.8,
+            },
+        );
+
+        let namespaces = HashMap::from([(4, "character".to_string())]);
+        let result = filter_and_process_tags(
+            tags,
+            &Thresholds::new(0.5),
+            &Rules::default(),
+            &TagFormat::default(),
+            &namespaces,
+        );
+        assert_eq!(result, vec!["1girl", "character:hatsune miku"]);
+    }
+
+    #[test]
+    fn test_tag_format() {
+        let keep = TagFormat::new(UnderscorePolicy::Keep, None, None).unwrap();
+        assert_eq!(keep.format("long_hair".to_string()), "long_hair");
+
+        let custom = TagFormat::new(UnderscorePolicy::Custom, Some("-"), None).unwrap();
+        assert_eq!(custom.format("long_hair".to_string()), "long-hair");
+        assert_eq!(custom.format("^_^".to_string()), "^_^");
+        assert!(TagFormat::new(UnderscorePolicy::Custom, None, None).is_err());
+    }
+
+    #[test]
+    fn test_tag_format_exceptions_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("exceptions.txt");
+        fs::write(&file_path, "o_o\n\n(^_^)/\n").unwrap();
+
+        let format =
+            TagFormat::new(UnderscorePolicy::Replace, None, Some(file_path.as_path())).unwrap();
+        assert_eq!(format.format("(^_^)/".to_string()), "(^_^)/");
+        assert_eq!(format.format("^_^".to_string()), "^ ^");
+        assert_eq!(format.format("o_o".to_string()), "o_o");
+    }
+
     #[test]
     fn test_decode_image() {
         let image_data = include_bytes!("../tests/test_image.jpg");